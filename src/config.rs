@@ -0,0 +1,636 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use async_signal::Signal;
+use evdev::Key;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    power::OnBattery, Percent, BOTTOM_OFFSET, COLS, DEFAULT_BRIGHTNESS, I2C_ADDRESS, LAYERS,
+    LEFT_OFFSET, MIN_BRIGHTNESS, RIGHT_OFFSET, ROWS, TOP_OFFSET,
+};
+
+pub type Layout = [[KeyName; COLS]; ROWS];
+
+const MAX_SEQUENCE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub enum DecimalSeparator {
+    #[default]
+    #[serde(rename = ".")]
+    Dot,
+    #[serde(rename = ",")]
+    Comma,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionAxes {
+    #[default]
+    Auto,
+    Mt,
+    St,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FingerEvent {
+    #[default]
+    Auto,
+    ToolFinger,
+    Touch,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoneAction {
+    #[default]
+    Ignore,
+    PassthroughTap,
+    Beep,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CornerPrecedence {
+    #[default]
+    Corner,
+    Cell,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct KeyName(pub Key);
+
+impl From<KeyName> for String {
+    fn from(KeyName(key): KeyName) -> Self {
+        format!("{key:?}")
+    }
+}
+
+impl TryFrom<String> for KeyName {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+            .map(Self)
+            .map_err(|_| format!("unknown key {name}"))
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Action {
+    Combo(Vec<Key>),
+    Backspaces(u8),
+}
+
+impl Action {
+    pub fn keys(&self) -> &[Key] {
+        match self {
+            Self::Combo(keys) => keys,
+            Self::Backspaces(_) => &[Key::KEY_BACKSPACE],
+        }
+    }
+}
+
+impl From<Action> for String {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Combo(keys) => keys
+                .iter()
+                .map(|key| format!("{key:?}"))
+                .collect::<Vec<_>>()
+                .join("+"),
+            Action::Backspaces(count) => format!("Backspaces({count})"),
+        }
+    }
+}
+
+fn combo_key(name: &str) -> Option<Key> {
+    let key = match name.to_ascii_lowercase().as_str() {
+        "super" | "meta" | "win" => Key::KEY_LEFTMETA,
+        "ctrl" | "control" => Key::KEY_LEFTCTRL,
+        "alt" => Key::KEY_LEFTALT,
+        "shift" => Key::KEY_LEFTSHIFT,
+        _ => {
+            return name
+                .parse()
+                .or_else(|_| format!("KEY_{}", name.to_ascii_uppercase()).parse())
+                .ok()
+        }
+    };
+    Some(key)
+}
+
+impl TryFrom<String> for Action {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if let Some(count) = name
+            .strip_prefix("Backspaces(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            return count
+                .trim()
+                .parse()
+                .map(Self::Backspaces)
+                .map_err(|e| format!("invalid backspace count {count}: {e}"));
+        }
+        name.split('+')
+            .map(|key| combo_key(key.trim()).ok_or_else(|| format!("unknown key {key}")))
+            .collect::<Result<_, _>>()
+            .map(Self::Combo)
+    }
+}
+
+const SIGNALS: [(&str, Signal); 8] = [
+    ("HUP", Signal::Hup),
+    ("INT", Signal::Int),
+    ("QUIT", Signal::Quit),
+    ("TERM", Signal::Term),
+    ("USR1", Signal::Usr1),
+    ("USR2", Signal::Usr2),
+    ("ALRM", Signal::Alarm),
+    ("WINCH", Signal::Winch),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SignalName(pub Signal);
+
+impl From<SignalName> for String {
+    fn from(SignalName(signal): SignalName) -> Self {
+        SIGNALS
+            .iter()
+            .find(|(_, s)| *s == signal)
+            .map_or_else(|| format!("{signal:?}"), |(name, _)| name.to_string())
+    }
+}
+
+impl TryFrom<String> for SignalName {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let upper = name.trim().to_ascii_uppercase();
+        let short = upper.strip_prefix("SIG").unwrap_or(&upper);
+        SIGNALS
+            .iter()
+            .find(|(n, _)| *n == short)
+            .map(|(_, signal)| Self(*signal))
+            .ok_or_else(|| format!("unknown signal {name}"))
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RowOffsets {
+    pub left_offset: Option<Percent>,
+    pub right_offset: Option<Percent>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub layers: Option<Vec<Layout>>,
+    pub left_offset: Option<Percent>,
+    pub right_offset: Option<Percent>,
+    pub top_offset: Option<Percent>,
+    pub bottom_offset: Option<Percent>,
+    pub brightness: Option<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub phys: Option<String>,
+    pub uniq: Option<String>,
+    pub udev_tag: Option<String>,
+    pub detect_timeout_secs: Option<u64>,
+    pub read_timeout_secs: u64,
+    pub control_socket: Option<PathBuf>,
+    pub uinput_path: Option<PathBuf>,
+    pub keyboards: u8,
+    pub wayland: bool,
+    pub stop_signals: Vec<SignalName>,
+    pub reset_signal: Option<SignalName>,
+    pub i2c_address: u16,
+    pub i2c_retries: u32,
+    pub i2c_retry_ms: u64,
+    pub i2c: bool,
+    pub activate_hex: Option<String>,
+    pub deactivate_hex: Option<String>,
+    pub left_offset: Percent,
+    pub right_offset: Percent,
+    pub top_offset: Percent,
+    pub bottom_offset: Percent,
+    pub row_offsets: Vec<RowOffsets>,
+    pub gutter: Percent,
+    pub square: bool,
+    pub numlock_width: Option<Percent>,
+    pub numlock_height: Option<Percent>,
+    pub numlock_edge_margin: Percent,
+    pub corner_precedence: CornerPrecedence,
+    pub calculator_width: Option<Percent>,
+    pub calculator_height: Option<Percent>,
+    pub fullpad: bool,
+    pub transpose: bool,
+    pub rotate: bool,
+    pub top_row_digits: bool,
+    pub auto_rotate: bool,
+    pub layers: Vec<Layout>,
+    pub brightness: u8,
+    pub profiles: Vec<Profile>,
+    pub calc_action: Action,
+    pub double_tap_ms: u64,
+    pub percent_double_tap: bool,
+    pub toggle_cooldown_ms: u64,
+    pub confirm_toggle: bool,
+    pub confirm_toggle_ms: u64,
+    pub sticky_numlock: bool,
+    pub sticky_numlock_ms: u64,
+    pub dim_after_ms: Option<u64>,
+    pub dim_brightness: u8,
+    pub fade_steps: u8,
+    pub fade_step_ms: u64,
+    pub hold_keys: BTreeMap<KeyName, KeyName>,
+    pub hold_time_ms: u64,
+    pub lift_debounce_ms: u64,
+    pub recalibrate_hold_ms: Option<u64>,
+    pub decimal_separator: DecimalSeparator,
+    pub backspace_as_delete: bool,
+    pub characters: BTreeMap<String, Action>,
+    pub actions: BTreeMap<KeyName, Action>,
+    pub equals_action: Option<Action>,
+    pub op_divide: Option<Action>,
+    pub op_multiply: Option<Action>,
+    pub op_minus: Option<Action>,
+    pub op_plus: Option<Action>,
+    pub emit_on_release: bool,
+    pub batch_events: bool,
+    pub xkb_numlock: bool,
+    pub on_enable_key: Option<KeyName>,
+    pub on_disable_key: Option<KeyName>,
+    pub tap_mode: bool,
+    pub shift_all: bool,
+    pub on_press_command: Option<Vec<String>>,
+    pub max_children: usize,
+    pub none_action: NoneAction,
+    pub corner_gate: bool,
+    pub track_contacts: bool,
+    pub scroll_strip: Option<Percent>,
+    pub scroll_step: Percent,
+    pub slide: bool,
+    pub merge_identical: bool,
+    pub slide_travel: Percent,
+    pub settle_ms: u64,
+    pub finger_event: FingerEvent,
+    pub position_axes: PositionAxes,
+    pub on_battery: OnBattery,
+    pub reset_on_start: bool,
+    pub no_grab: bool,
+    pub regrab_after_activate_ms: Option<u64>,
+    pub button_mode: bool,
+    pub verbose: bool,
+    pub tee_log: bool,
+    pub heatmap: bool,
+    pub vt: Option<u32>,
+    pub release_on_session_change: bool,
+    pub keep_across_vt: bool,
+    pub disable_file: Option<PathBuf>,
+    pub master_key: Option<KeyName>,
+    pub locked_command: Option<String>,
+    pub locked_off: bool,
+    pub state_file: Option<PathBuf>,
+    pub state_fifo: Option<PathBuf>,
+    #[serde(skip)]
+    pub learn: bool,
+    #[serde(skip)]
+    pub check: bool,
+    #[serde(skip)]
+    pub dump_config: bool,
+    #[serde(skip)]
+    pub print_grid: bool,
+    #[serde(skip)]
+    pub measure_latency: bool,
+    #[serde(skip)]
+    pub export_svg: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            phys: None,
+            uniq: None,
+            udev_tag: None,
+            detect_timeout_secs: None,
+            read_timeout_secs: 30,
+            control_socket: None,
+            uinput_path: None,
+            keyboards: 1,
+            wayland: false,
+            stop_signals: vec![
+                SignalName(Signal::Term),
+                SignalName(Signal::Quit),
+                SignalName(Signal::Int),
+            ],
+            reset_signal: None,
+            i2c_address: I2C_ADDRESS,
+            i2c_retries: 2,
+            i2c_retry_ms: 20,
+            i2c: true,
+            activate_hex: None,
+            deactivate_hex: None,
+            left_offset: LEFT_OFFSET,
+            row_offsets: Vec::new(),
+            right_offset: RIGHT_OFFSET,
+            top_offset: TOP_OFFSET,
+            bottom_offset: BOTTOM_OFFSET,
+            gutter: Percent(0),
+            square: false,
+            numlock_width: None,
+            numlock_height: None,
+            numlock_edge_margin: Percent(0),
+            corner_precedence: CornerPrecedence::Corner,
+            calculator_width: None,
+            calculator_height: None,
+            fullpad: false,
+            transpose: false,
+            rotate: false,
+            top_row_digits: false,
+            auto_rotate: false,
+            layers: LAYERS.map(|layer| layer.map(|row| row.map(KeyName))).into(),
+            brightness: DEFAULT_BRIGHTNESS,
+            profiles: Vec::new(),
+            calc_action: Action::Combo(vec![Key::KEY_CALC]),
+            double_tap_ms: 300,
+            percent_double_tap: false,
+            toggle_cooldown_ms: 300,
+            confirm_toggle: false,
+            confirm_toggle_ms: 1000,
+            sticky_numlock: false,
+            sticky_numlock_ms: 150,
+            dim_after_ms: None,
+            dim_brightness: MIN_BRIGHTNESS,
+            fade_steps: 0,
+            fade_step_ms: 20,
+            hold_keys: BTreeMap::new(),
+            hold_time_ms: 300,
+            lift_debounce_ms: 0,
+            recalibrate_hold_ms: None,
+            decimal_separator: DecimalSeparator::Dot,
+            backspace_as_delete: false,
+            characters: BTreeMap::new(),
+            actions: BTreeMap::new(),
+            equals_action: None,
+            op_divide: None,
+            op_multiply: None,
+            op_minus: None,
+            op_plus: None,
+            emit_on_release: false,
+            batch_events: false,
+            xkb_numlock: false,
+            on_enable_key: None,
+            on_disable_key: None,
+            tap_mode: false,
+            shift_all: false,
+            on_press_command: None,
+            max_children: 4,
+            none_action: NoneAction::Ignore,
+            corner_gate: false,
+            track_contacts: true,
+            scroll_strip: None,
+            scroll_step: Percent(5),
+            slide: false,
+            merge_identical: false,
+            slide_travel: Percent(0),
+            settle_ms: 0,
+            finger_event: FingerEvent::Auto,
+            position_axes: PositionAxes::Auto,
+            on_battery: OnBattery::Normal,
+            reset_on_start: false,
+            no_grab: false,
+            regrab_after_activate_ms: None,
+            button_mode: false,
+            verbose: false,
+            tee_log: false,
+            heatmap: false,
+            vt: None,
+            release_on_session_change: false,
+            keep_across_vt: false,
+            disable_file: None,
+            master_key: None,
+            locked_command: None,
+            locked_off: false,
+            state_file: None,
+            state_fifo: None,
+            learn: false,
+            check: false,
+            dump_config: false,
+            print_grid: false,
+            measure_latency: false,
+            export_svg: None,
+        }
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+fn value(args: &mut impl Iterator<Item = String>, arg: &str) -> std::io::Result<String> {
+    args.next()
+        .ok_or_else(|| invalid(format!("{arg} requires a value")))
+}
+
+fn parse_address(address: &str) -> std::io::Result<u16> {
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    parsed
+        .ok()
+        .filter(|address| (0x03..=0x77).contains(address))
+        .ok_or_else(|| invalid(format!("invalid i2c address {address}")))
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let bytes = hex
+        .split_whitespace()
+        .map(|byte| {
+            let byte = byte.strip_prefix("0x").unwrap_or(byte);
+            (byte.len() <= 2).then_some(())?;
+            u8::from_str_radix(byte, 16).ok()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (1..=MAX_SEQUENCE).contains(&bytes.len()).then_some(bytes)
+}
+
+fn sequence(name: &str, hex: &Option<String>) -> Option<Vec<u8>> {
+    let hex = hex.as_ref()?;
+    let sequence = parse_hex(hex);
+    if sequence.is_none() {
+        log::warn!("invalid {name} {hex:?}, using the built-in sequence");
+    }
+    sequence
+}
+
+impl Config {
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let (config, json) = if path == Path::new("-") {
+            let config = std::io::read_to_string(std::io::stdin())?;
+            let json = config.trim_start().starts_with('{');
+            (config, json)
+        } else {
+            let json = path
+                .extension()
+                .is_some_and(|extension| extension == "json");
+            (std::fs::read_to_string(path)?, json)
+        };
+        let config: Self = if json {
+            serde_json::from_str(&config).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&config).map_err(|e| e.to_string())
+        }
+        .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+        if config
+            .profiles()
+            .iter()
+            .any(|profile| profile.layers.is_empty())
+        {
+            return Err(invalid(format!("{}: no layers", path.display())));
+        }
+        if config.read_timeout_secs == 0 {
+            return Err(invalid(format!(
+                "{}: read_timeout_secs must be at least 1",
+                path.display()
+            )));
+        }
+        Ok(config)
+    }
+
+    pub fn from_args() -> std::io::Result<Self> {
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => match args.get(i + 1) {
+                Some(path) => Self::load(path.as_ref())?,
+                None => return Err(invalid("--config requires a value".into())),
+            },
+            None => Self::default(),
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    value(&mut args, &arg)?;
+                }
+                "--phys" => config.phys = Some(value(&mut args, &arg)?),
+                "--uniq" => config.uniq = Some(value(&mut args, &arg)?),
+                "--udev-tag" => config.udev_tag = Some(value(&mut args, &arg)?),
+                "--i2c-address" => config.i2c_address = parse_address(&value(&mut args, &arg)?)?,
+                "--detect-timeout-secs" => {
+                    let secs = value(&mut args, &arg)?;
+                    config.detect_timeout_secs = Some(
+                        secs.parse()
+                            .map_err(|_| invalid(format!("invalid {arg} {secs}")))?,
+                    );
+                }
+                "--i2c-retries" => {
+                    let retries = value(&mut args, &arg)?;
+                    config.i2c_retries = retries
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid {arg} {retries}")))?;
+                }
+                "--control-socket" => {
+                    config.control_socket = Some(value(&mut args, &arg)?.into());
+                }
+                "--stop-signals" => {
+                    config.stop_signals = value(&mut args, &arg)?
+                        .split(',')
+                        .map(|name| SignalName::try_from(name.to_owned()).map_err(invalid))
+                        .collect::<std::io::Result<_>>()?;
+                }
+                "--state-file" => config.state_file = Some(value(&mut args, &arg)?.into()),
+                "--state-fifo" => config.state_fifo = Some(value(&mut args, &arg)?.into()),
+                "--with-tab" => config.with_tab(),
+                "--fullpad" => config.fullpad(),
+                "--transpose" => config.transpose = true,
+                "--rotate" => config.rotate = true,
+                "--top-row-digits" => config.top_row_digits = true,
+                "--uinput-path" => config.uinput_path = Some(value(&mut args, &arg)?.into()),
+                "-v" | "--verbose" => config.verbose = true,
+                "--tee-log" => config.tee_log = true,
+                "--reset-on-start" => config.reset_on_start = true,
+                "--no-grab" => config.no_grab = true,
+                "--learn" => config.learn = true,
+                "--check" => config.check = true,
+                "--dump-config" => config.dump_config = true,
+                "--print-grid" => config.print_grid = true,
+                "--measure-latency" => config.measure_latency = true,
+                "--export-svg" => config.export_svg = Some(value(&mut args, &arg)?.into()),
+                _ => return Err(invalid(format!("unknown argument {arg}"))),
+            }
+        }
+        Ok(config)
+    }
+
+    fn with_tab(&mut self) {
+        for layers in std::iter::once(&mut self.layers).chain(
+            self.profiles
+                .iter_mut()
+                .filter_map(|profile| profile.layers.as_mut()),
+        ) {
+            for KeyName(key) in layers.iter_mut().flatten().flatten() {
+                if *key == Key::KEY_KPEQUAL {
+                    *key = Key::KEY_TAB;
+                }
+            }
+        }
+    }
+
+    pub fn fullpad(&mut self) {
+        self.fullpad = true;
+        self.left_offset = Percent(0);
+        self.right_offset = Percent(0);
+        self.top_offset = Percent(0);
+        self.bottom_offset = Percent(0);
+        self.row_offsets.clear();
+        for profile in &mut self.profiles {
+            profile.left_offset = None;
+            profile.right_offset = None;
+            profile.top_offset = None;
+            profile.bottom_offset = None;
+        }
+    }
+
+    pub fn to_toml(&self) -> std::io::Result<String> {
+        toml::to_string(self).map_err(|e| std::io::Error::other(e.to_string()))
+    }
+
+    pub fn profiles(&self) -> Vec<Self> {
+        std::iter::once(self.clone())
+            .chain(self.profiles.iter().map(|profile| {
+                let mut config = self.clone();
+                if let Some(layers) = &profile.layers {
+                    config.layers.clone_from(layers);
+                }
+                config.left_offset = profile.left_offset.unwrap_or(self.left_offset);
+                config.right_offset = profile.right_offset.unwrap_or(self.right_offset);
+                config.top_offset = profile.top_offset.unwrap_or(self.top_offset);
+                config.bottom_offset = profile.bottom_offset.unwrap_or(self.bottom_offset);
+                config.brightness = profile.brightness.unwrap_or(self.brightness);
+                config
+            }))
+            .collect()
+    }
+
+    pub fn activate_sequence(&self) -> Option<Vec<u8>> {
+        sequence("activate_hex", &self.activate_hex)
+    }
+
+    pub fn deactivate_sequence(&self) -> Option<Vec<u8>> {
+        sequence("deactivate_hex", &self.deactivate_hex)
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use async_channel::Sender;
+use async_executor::LocalExecutor;
+use async_io::Async;
+use futures_lite::{io::BufReader, AsyncBufReadExt, AsyncWriteExt, StreamExt};
+
+pub enum Command {
+    On,
+    Off,
+    Toggle,
+    Brightness(u8),
+    CycleBrightness,
+    Layer(usize),
+    Status,
+    Reset,
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+impl FromStr for Command {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("on"), None) => Self::On,
+            (Some("off"), None) => Self::Off,
+            (Some("toggle"), None) => Self::Toggle,
+            (Some("status"), None) => Self::Status,
+            (Some("reset"), None) => Self::Reset,
+            (Some("brightness"), Some(n)) => Self::Brightness(
+                n.parse()
+                    .map_err(|_| invalid(format!("invalid brightness {n}")))?,
+            ),
+            (Some("layer"), Some(n)) => Self::Layer(
+                n.parse()
+                    .map_err(|_| invalid(format!("invalid layer {n}")))?,
+            ),
+            _ => return Err(invalid(format!("unknown command {s}"))),
+        };
+        if words.next().is_some() {
+            return Err(invalid(format!("unknown command {s}")));
+        }
+        Ok(command)
+    }
+}
+
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<String>,
+}
+
+struct Listener {
+    listener: Async<UnixListener>,
+    path: PathBuf,
+}
+
+impl Listener {
+    fn bind(path: &Path) -> std::io::Result<Self> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Ok(Self {
+            listener: Async::<UnixListener>::bind(path)?,
+            path: path.into(),
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::error!("{e}")
+        }
+    }
+}
+
+async fn handle(stream: Async<UnixStream>, requests: Sender<Request>) -> std::io::Result<()> {
+    let mut lines = BufReader::new(&stream).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse() {
+            Ok(command) => {
+                let (reply, replied) = async_channel::bounded(1);
+                if requests.send(Request { command, reply }).await.is_err() {
+                    break;
+                }
+                replied
+                    .recv()
+                    .await
+                    .unwrap_or_else(|e| format!("error: {e}"))
+            }
+            Err(e) => format!("error: {e}"),
+        };
+        (&stream).write_all(format!("{reply}\n").as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub async fn serve(path: &Path, requests: Sender<Request>) -> std::io::Result<()> {
+    let listener = Listener::bind(path)?;
+    log::info!("control socket {}", path.display());
+    let executor = LocalExecutor::new();
+    executor
+        .run(async {
+            loop {
+                let (stream, _) = listener.listener.accept().await?;
+                let requests = requests.clone();
+                executor
+                    .spawn(async move {
+                        if let Err(e) = handle(stream, requests).await {
+                            log::error!("{e}")
+                        }
+                    })
+                    .detach();
+            }
+        })
+        .await
+}
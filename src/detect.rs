@@ -0,0 +1,253 @@
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+
+use crate::{config::Config, TRY_SLEEP, TRY_TIMES};
+
+fn selected(selector: &Option<String>, value: &Option<String>) -> bool {
+    selector.is_none() || selector == value
+}
+
+fn fields(block: &[&str]) -> Vec<(char, String)> {
+    let mut fields: Vec<(char, String)> = Vec::new();
+    for line in block.iter().map(|line| line.trim()) {
+        let mut chars = line.chars();
+        match (chars.next(), chars.next()) {
+            (Some(kind), Some(':')) if kind.is_ascii_uppercase() => {
+                fields.push((kind, chars.as_str().trim().into()))
+            }
+            _ => {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push(' ');
+                    value.push_str(line);
+                }
+            }
+        }
+    }
+    fields
+}
+
+fn field<'a>(fields: &'a [(char, String)], kind: char, key: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .filter(|(field, _)| *field == kind)
+        .find_map(|(_, value)| value.strip_prefix(key))
+}
+
+fn touchpad(
+    fields: &[(char, String)],
+    config: &Config,
+    i2c: &regex::Regex,
+) -> Option<(String, String)> {
+    let name = field(fields, 'N', "Name=")?;
+    if !(name.contains("Touchpad") && (name.starts_with("\"ASUE") || name.starts_with("\"ELAN"))) {
+        return None;
+    }
+    let phys = field(fields, 'P', "Phys=").map(String::from);
+    let uniq = field(fields, 'U', "Uniq=").map(String::from);
+    if !(selected(&config.phys, &phys) && selected(&config.uniq, &uniq)) {
+        return None;
+    }
+    let device_id = i2c.captures(field(fields, 'S', "Sysfs=")?)?[1].into();
+    let touchpad = field(fields, 'H', "Handlers=")?
+        .split_whitespace()
+        .find_map(|handler| handler.strip_prefix("event"))?;
+    Some((touchpad.into(), device_id))
+}
+
+fn find(devices: &str, config: &Config, i2c: &regex::Regex) -> Option<(String, String)> {
+    let lines = devices.lines().collect::<Vec<_>>();
+    lines
+        .split(|line| line.trim().is_empty())
+        .find_map(|block| touchpad(&fields(block), config, i2c))
+}
+
+#[cfg(feature = "udev")]
+fn tagged(tag: &str, i2c: &regex::Regex) -> std::io::Result<Option<(String, String)>> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("input")?;
+    enumerator.match_tag(tag)?;
+    for device in enumerator.scan_devices()? {
+        let Some(touchpad) = device
+            .sysname()
+            .to_str()
+            .and_then(|name| name.strip_prefix("event"))
+        else {
+            continue;
+        };
+        let syspath = device.syspath().to_string_lossy();
+        if let Some(captures) = i2c.captures(&syspath) {
+            log::info!(
+                "tagged {}",
+                device.devnode().unwrap_or(device.syspath()).display()
+            );
+            return Ok(Some((touchpad.into(), captures[1].into())));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(feature = "udev"))]
+fn tagged(_: &str, _: &regex::Regex) -> std::io::Result<Option<(String, String)>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "built without the udev feature",
+    ))
+}
+
+pub async fn detect(config: &Config) -> std::io::Result<(String, String)> {
+    let mut tries = TRY_TIMES;
+    let deadline = config
+        .detect_timeout_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let i2c = regex::Regex::new(".*i2c-(\\d+)/.*$").unwrap();
+    let (touchpad, device_id) = loop {
+        if let Some(tag) = &config.udev_tag {
+            match tagged(tag, &i2c) {
+                Ok(Some(found)) => break found,
+                Ok(None) => log::warn!("no i2c input device tagged {tag}"),
+                Err(e) => log::warn!("{e}"),
+            }
+        }
+        let devices = std::fs::read_to_string("/proc/bus/input/devices")?;
+        match find(&devices, config, &i2c) {
+            Some(found) => break found,
+            None => log::error!("bwaaa"),
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "no touchpad found within detect_timeout_secs",
+                ));
+            }
+            Some(_) => {}
+            None => {
+                tries -= 1;
+                if tries == 0 {
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+        Timer::after(TRY_SLEEP).await;
+    };
+    Ok((touchpad, device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYBOARD: &str = r#"I: Bus=0011 Vendor=0001 Product=0001 Version=ab83
+N: Name="AT Translated Set 2 keyboard"
+P: Phys=isa0060/serio0/input0
+S: Sysfs=/devices/platform/i8042/serio0/input/input3
+U: Uniq=
+H: Handlers=sysrq kbd leds event3
+B: PROP=0
+B: EV=120013
+"#;
+
+    const MOUSE: &str = r#"I: Bus=0018 Vendor=04f3 Product=319f Version=0100
+N: Name="ASUE1209:00 04F3:319F Mouse"
+P: Phys=i2c-ASUE1209:00
+S: Sysfs=/devices/pci0000:00/0000:00:15.1/i2c_designware.1/i2c-1/i2c-ASUE1209:00/0018:04F3:319F.0001/input/input15
+U: Uniq=
+H: Handlers=mouse1 event8
+B: PROP=0
+B: EV=17
+"#;
+
+    const TOUCHPAD: &str = r#"I: Bus=0018 Vendor=04f3 Product=319f Version=0100
+N: Name="ASUE1209:00 04F3:319F Touchpad"
+P: Phys=i2c-ASUE1209:00
+S: Sysfs=/devices/pci0000:00/0000:00:15.1/i2c_designware.1/i2c-1/i2c-ASUE1209:00/0018:04F3:319F.0001/input/input16
+U: Uniq=
+H: Handlers=mouse2 event9
+B: PROP=5
+B: EV=1b
+"#;
+
+    fn found(devices: &str, config: &Config) -> Option<(String, String)> {
+        find(
+            devices,
+            config,
+            &regex::Regex::new(".*i2c-(\\d+)/.*$").unwrap(),
+        )
+    }
+
+    #[test]
+    fn finds_the_touchpad_among_other_devices() {
+        let devices = [KEYBOARD, MOUSE, TOUCHPAD].join("\n");
+        assert_eq!(
+            found(&devices, &Config::default()),
+            Some(("9".into(), "1".into())),
+        );
+    }
+
+    #[test]
+    fn handlers_before_sysfs() {
+        let devices = r#"I: Bus=0018 Vendor=04f3 Product=3098 Version=0100
+N: Name="ELAN1406:00 04F3:3098 Touchpad"
+H: Handlers=event12 mouse3
+P: Phys=i2c-ELAN1406:00
+U: Uniq=
+S: Sysfs=/devices/platform/AMDI0010:03/i2c-0/i2c-ELAN1406:00/0018:04F3:3098.0002/input/input21
+"#;
+        assert_eq!(
+            found(devices, &Config::default()),
+            Some(("12".into(), "0".into())),
+        );
+    }
+
+    #[test]
+    fn uneven_spacing() {
+        let devices = concat!(
+            "  I: Bus=0018 Vendor=04f3 Product=319f Version=0100\r\n",
+            "  N:  Name=\"ASUE1209:00 04F3:319F Touchpad\"\r\n",
+            "  S:   Sysfs=/devices/pci0000:00/i2c-2/i2c-ASUE1209:00/input/input16\r\n",
+            "  H: Handlers=mouse2   event9 \r\n",
+            " \r\n",
+        );
+        assert_eq!(
+            found(devices, &Config::default()),
+            Some(("9".into(), "2".into())),
+        );
+    }
+
+    #[test]
+    fn name_across_lines() {
+        let devices = r#"I: Bus=0018 Vendor=04f3 Product=3098 Version=0100
+N: Name="ELAN1406:00 04F3:3098
+    Touchpad"
+P: Phys=i2c-ELAN1406:00
+S: Sysfs=/devices/platform/AMDI0010:03/i2c-0/i2c-ELAN1406:00/0018:04F3:3098.0002/input/input21
+U: Uniq=
+H: Handlers=mouse3 event12
+"#;
+        assert_eq!(
+            found(devices, &Config::default()),
+            Some(("12".into(), "0".into())),
+        );
+    }
+
+    #[test]
+    fn phys_selects_a_touchpad() {
+        let other = TOUCHPAD
+            .replace("i2c-ASUE1209:00", "i2c-ASUE1209:01")
+            .replace("i2c-1/", "i2c-3/")
+            .replace("event9", "event10");
+        let devices = [TOUCHPAD, &other].join("\n");
+        let config = Config {
+            phys: Some("i2c-ASUE1209:01".into()),
+            ..Config::default()
+        };
+        assert_eq!(found(&devices, &config), Some(("10".into(), "3".into())));
+    }
+
+    #[test]
+    fn no_touchpad() {
+        let devices = [KEYBOARD, MOUSE].join("\n");
+        assert_eq!(found(&devices, &Config::default()), None);
+    }
+}
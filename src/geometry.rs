@@ -0,0 +1,256 @@
+use crate::{
+    config::{Config, RowOffsets},
+    Percent, COLS, ROWS,
+};
+
+#[derive(Clone, Copy)]
+pub struct Geometry {
+    pub minx: i32,
+    pub maxx: i32,
+    pub miny: i32,
+    pub maxy: i32,
+    pub left_offset: Percent,
+    pub right_offset: Percent,
+    pub top_offset: Percent,
+    pub bottom_offset: Percent,
+    pub row_offsets: [RowOffsets; ROWS],
+    pub gutter: Percent,
+    pub square: bool,
+}
+
+pub fn non_neg_sub(a: i32, b: i32) -> Option<i32> {
+    let x = a.checked_sub(b)?;
+    (x >= 0).then_some(x)
+}
+
+fn cell(offset: i32, cells: usize, size: i32, gutter: Percent) -> Option<usize> {
+    let scaled = offset * i32::try_from(cells).ok()?;
+    let span = size + 1;
+    let cell = scaled.checked_div(span)?.try_into().ok()?;
+    let within = scaled.checked_rem(span)?;
+    let half = gutter * span / 2;
+    let in_gutter = (cell > 0 && within < half) || (cell + 1 < cells && within >= span - half);
+    (cell < cells && !in_gutter).then_some(cell)
+}
+
+impl Geometry {
+    pub fn new((minx, maxx, miny, maxy): (i32, i32, i32, i32), config: &Config) -> Self {
+        Self {
+            minx,
+            maxx,
+            miny,
+            maxy,
+            left_offset: config.left_offset,
+            right_offset: config.right_offset,
+            top_offset: config.top_offset,
+            bottom_offset: config.bottom_offset,
+            row_offsets: std::array::from_fn(|row| {
+                config.row_offsets.get(row).copied().unwrap_or_default()
+            }),
+            gutter: config.gutter,
+            square: config.square,
+        }
+    }
+
+    pub fn in_row(&self, row: usize) -> Self {
+        let RowOffsets {
+            left_offset,
+            right_offset,
+        } = self.row_offsets[row];
+        Self {
+            left_offset: left_offset.unwrap_or(self.left_offset),
+            right_offset: right_offset.unwrap_or(self.right_offset),
+            ..*self
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.maxx - self.minx
+    }
+
+    pub fn height(&self) -> i32 {
+        self.maxy - self.miny
+    }
+
+    fn np(&self) -> (i32, i32, i32, i32) {
+        let left = self.minx + self.left_offset * self.width();
+        let right = self.maxx - self.right_offset * self.width();
+        let top = self.miny + self.top_offset * self.height();
+        let bottom = self.maxy - self.bottom_offset * self.height();
+        if !self.square {
+            return (left, right, top, bottom);
+        }
+        let side = (right - left).min(bottom - top);
+        let (x, y) = ((right - left - side) / 2, (bottom - top - side) / 2);
+        (left + x, left + x + side, top + y, top + y + side)
+    }
+
+    pub fn left_np(&self) -> i32 {
+        self.np().0
+    }
+
+    pub fn right_np(&self) -> i32 {
+        self.np().1
+    }
+
+    pub fn top_np(&self) -> i32 {
+        self.np().2
+    }
+
+    pub fn bottom_np(&self) -> i32 {
+        self.np().3
+    }
+
+    pub fn width_np(&self) -> i32 {
+        self.right_np() - self.left_np()
+    }
+
+    pub fn height_np(&self) -> i32 {
+        self.bottom_np() - self.top_np()
+    }
+
+    pub fn column(&self, x: i32) -> Option<usize> {
+        cell(
+            non_neg_sub(x, self.left_np())?,
+            COLS,
+            self.width_np(),
+            self.gutter,
+        )
+    }
+
+    pub fn row(&self, y: i32) -> Option<usize> {
+        cell(
+            non_neg_sub(y, self.top_np())?,
+            ROWS,
+            self.height_np(),
+            self.gutter,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GEOMETRY: Geometry = Geometry {
+        minx: 0,
+        maxx: 1000,
+        miny: 0,
+        maxy: 500,
+        left_offset: Percent(10),
+        right_offset: Percent(10),
+        top_offset: Percent(10),
+        bottom_offset: Percent(10),
+        row_offsets: [RowOffsets {
+            left_offset: None,
+            right_offset: None,
+        }; ROWS],
+        gutter: Percent(0),
+        square: false,
+    };
+
+    #[test]
+    fn non_neg_sub_bounds() {
+        assert_eq!(non_neg_sub(5, 5), Some(0));
+        assert_eq!(non_neg_sub(5, 6), None);
+        assert_eq!(non_neg_sub(6, 5), Some(1));
+        assert_eq!(non_neg_sub(i32::MIN, 1), None);
+    }
+
+    #[test]
+    fn grid_bounds() {
+        assert_eq!(GEOMETRY.left_np(), 100);
+        assert_eq!(GEOMETRY.right_np(), 900);
+        assert_eq!(GEOMETRY.top_np(), 50);
+        assert_eq!(GEOMETRY.bottom_np(), 450);
+    }
+
+    #[test]
+    fn left_edge_is_first_column() {
+        assert_eq!(GEOMETRY.column(GEOMETRY.left_np()), Some(0));
+        assert_eq!(GEOMETRY.column(GEOMETRY.left_np() - 1), None);
+    }
+
+    #[test]
+    fn right_edge_is_last_column() {
+        assert_eq!(GEOMETRY.column(GEOMETRY.right_np()), Some(COLS - 1));
+        assert_eq!(GEOMETRY.column(GEOMETRY.right_np() + 1), None);
+        assert_eq!(GEOMETRY.column(GEOMETRY.maxx), None);
+    }
+
+    #[test]
+    fn top_and_bottom_edges() {
+        assert_eq!(GEOMETRY.row(GEOMETRY.top_np()), Some(0));
+        assert_eq!(GEOMETRY.row(GEOMETRY.top_np() - 1), None);
+        assert_eq!(GEOMETRY.row(GEOMETRY.bottom_np()), Some(ROWS - 1));
+        assert_eq!(GEOMETRY.row(GEOMETRY.bottom_np() + 1), None);
+    }
+
+    #[test]
+    fn columns_are_even() {
+        let columns = (GEOMETRY.left_np()..=GEOMETRY.right_np())
+            .map(|x| GEOMETRY.column(x).unwrap())
+            .collect::<Vec<_>>();
+        for column in 0..COLS {
+            let width = columns.iter().filter(|c| **c == column).count();
+            assert!(width.abs_diff(columns.len() / COLS) <= 1);
+        }
+    }
+
+    #[test]
+    fn gutters_between_cells() {
+        let geometry = Geometry {
+            gutter: Percent(20),
+            ..GEOMETRY
+        };
+        assert_eq!(geometry.column(geometry.left_np()), Some(0));
+        assert_eq!(geometry.column(geometry.right_np()), Some(COLS - 1));
+        let boundary = geometry.left_np() + (geometry.width_np() + 1) / 5;
+        assert_eq!(geometry.column(boundary), None);
+        assert_eq!(geometry.column(boundary - 1), None);
+        assert_eq!(geometry.column(boundary - 17), Some(0));
+        assert_eq!(geometry.column(boundary + 17), Some(1));
+    }
+
+    #[test]
+    fn row_offsets_override_one_row() {
+        let mut geometry = GEOMETRY;
+        geometry.row_offsets[0].left_offset = Some(Percent(20));
+        assert_eq!(geometry.in_row(0).left_np(), 200);
+        assert_eq!(geometry.in_row(0).right_np(), 900);
+        assert_eq!(geometry.in_row(1).left_np(), 100);
+        assert_eq!(geometry.in_row(0).column(150), None);
+        assert_eq!(geometry.in_row(1).column(150), Some(0));
+    }
+
+    #[test]
+    fn square_grid_is_centered() {
+        let geometry = Geometry {
+            square: true,
+            ..GEOMETRY
+        };
+        assert_eq!(geometry.width_np(), 400);
+        assert_eq!(geometry.height_np(), 400);
+        assert_eq!(geometry.left_np(), 300);
+        assert_eq!(geometry.top_np(), 50);
+        assert_eq!(geometry.column(200), None);
+    }
+
+    #[test]
+    fn empty_grid() {
+        let geometry = Geometry {
+            left_offset: Percent(50),
+            right_offset: Percent(50),
+            ..GEOMETRY
+        };
+        assert_eq!(geometry.width_np(), 0);
+        assert_eq!(geometry.column(500), Some(0));
+        let geometry = Geometry {
+            left_offset: Percent(60),
+            right_offset: Percent(60),
+            ..GEOMETRY
+        };
+        assert_eq!(geometry.column(500), None);
+        assert_eq!(geometry.column(700), None);
+    }
+}
@@ -0,0 +1,47 @@
+const CORNERS: [&str; 4] = ["top left", "top right", "bottom right", "bottom left"];
+
+pub struct Learn {
+    taps: Vec<(i32, i32)>,
+}
+
+impl Learn {
+    pub fn new() -> Self {
+        let learn = Self { taps: Vec::new() };
+        learn.prompt();
+        learn
+    }
+
+    fn prompt(&self) {
+        if let Some(corner) = CORNERS.get(self.taps.len()) {
+            log::info!("tap the {corner} corner of the numpad");
+        }
+    }
+
+    pub fn tap(&mut self, x: i32, y: i32) {
+        if !self.done() {
+            log::info!("x {x}  y {y}");
+            self.taps.push((x, y));
+            self.prompt();
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.taps.len() == CORNERS.len()
+    }
+
+    pub fn left(&self) -> i32 {
+        self.taps[0].0.min(self.taps[3].0)
+    }
+
+    pub fn right(&self) -> i32 {
+        self.taps[1].0.max(self.taps[2].0)
+    }
+
+    pub fn top(&self) -> i32 {
+        self.taps[0].1.min(self.taps[1].1)
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.taps[2].1.max(self.taps[3].1)
+    }
+}
@@ -1,27 +1,59 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader},
+    collections::{BTreeMap, BTreeSet},
     ops::Mul,
     os::fd::AsRawFd,
-    path::Path,
-    time::Duration,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
+use async_channel::Receiver;
 use async_io::{Async, Timer};
 use async_signal::{Signal, Signals};
 use evdev::{
     uinput::{VirtualDevice, VirtualDeviceBuilder},
     AbsoluteAxisType, AttributeSet, Device, EventType, InputEvent, InputEventKind, Key,
-    Synchronization,
+    RelativeAxisType, SoundType, SwitchType, Synchronization,
 };
 use futures_lite::{FutureExt, StreamExt};
 use i2cdev::{
     core::I2CTransfer,
     linux::{I2CMessage, LinuxI2CDevice},
 };
-use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+};
+use serde::{Deserialize, Serialize};
+
+use self::{
+    config::{
+        Action, Config, CornerPrecedence, DecimalSeparator, FingerEvent, KeyName, NoneAction,
+        PositionAxes, SignalName,
+    },
+    control::{Command, Request},
+    geometry::Geometry,
+    learn::Learn,
+    power::OnBattery,
+    sink::{KeySink, LogSink, RawDevice, Tee},
+    state::State,
+    svg::Svg,
+};
+
+mod config;
+mod control;
+mod detect;
+mod geometry;
+mod learn;
+mod models;
+mod power;
+mod sink;
+mod state;
+mod svg;
+mod vt;
+mod wayland;
 
-#[derive(PartialEq, PartialOrd, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, Serialize)]
+#[serde(transparent)]
 struct Percent(i32);
 
 impl Percent {
@@ -38,14 +70,39 @@ impl Mul<i32> for Percent {
     }
 }
 
+nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+
+const KEYBOARD_NAME: &str = "Asus Touchpad/Numpad";
 const TRY_TIMES: usize = 5;
 const TRY_SLEEP: Duration = Duration::from_millis(100);
+const GRAB_TRY_TIMES: usize = 5;
+const GRAB_TRY_SLEEP: Duration = Duration::from_secs(1);
 const COLS: usize = 5;
 const ROWS: usize = 4;
 const LEFT_OFFSET: Percent = Percent(7);
 const RIGHT_OFFSET: Percent = Percent(7);
 const TOP_OFFSET: Percent = Percent(10);
 const BOTTOM_OFFSET: Percent = Percent(4);
+const I2C_ADDRESS: u16 = 0x15;
+const MAX_I2C_RETRIES: u32 = 10;
+const DEFAULT_BRIGHTNESS: u8 = 0x01;
+const MIN_BRIGHTNESS: u8 = 0x01;
+const BRIGHTNESS_LEVELS: [u8; 3] = [MIN_BRIGHTNESS, 0x18, 0x1f];
+const POWER_POLL: Duration = Duration::from_secs(5);
+const DISABLE_POLL: Duration = Duration::from_secs(1);
+const LOCK_POLL: Duration = Duration::from_secs(1);
+const LOCK_CHECK_POLL: Duration = Duration::from_millis(100);
+const SESSION_POLL: Duration = Duration::from_millis(250);
+const ON_PRESS_THROTTLE: Duration = Duration::from_millis(50);
+const BELL: Duration = Duration::from_millis(100);
+const TOOLS: [Key; 5] = [
+    Key::BTN_TOOL_FINGER,
+    Key::BTN_TOOL_DOUBLETAP,
+    Key::BTN_TOOL_TRIPLETAP,
+    Key::BTN_TOOL_QUADTAP,
+    Key::BTN_TOOL_QUINTTAP,
+];
+const LAYERS: [[[Key; COLS]; ROWS]; 2] = [KEYS, NAVIGATION];
 const KEYS: [[Key; COLS]; ROWS] = [
     [
         Key::KEY_KP7,
@@ -76,358 +133,2336 @@ const KEYS: [[Key; COLS]; ROWS] = [
         Key::KEY_KPEQUAL,
     ],
 ];
-
-enum Touchpad {
-    No,
-    Yes,
-    Some(String),
-}
+const NAVIGATION: [[Key; COLS]; ROWS] = [
+    [
+        Key::KEY_HOME,
+        Key::KEY_UP,
+        Key::KEY_PAGEUP,
+        Key::KEY_KPSLASH,
+        Key::KEY_BACKSPACE,
+    ],
+    [
+        Key::KEY_LEFT,
+        Key::KEY_KP5,
+        Key::KEY_RIGHT,
+        Key::KEY_KPASTERISK,
+        Key::KEY_BACKSPACE,
+    ],
+    [
+        Key::KEY_END,
+        Key::KEY_DOWN,
+        Key::KEY_PAGEDOWN,
+        Key::KEY_KPMINUS,
+        Key::KEY_5,
+    ],
+    [
+        Key::KEY_INSERT,
+        Key::KEY_DELETE,
+        Key::KEY_KPENTER,
+        Key::KEY_KPPLUS,
+        Key::KEY_KPEQUAL,
+    ],
+];
 
 fn main() -> std::io::Result<()> {
+    let config = Config::from_args()?;
+    if config.dump_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
     env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(if config.verbose {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Info
+        })
         .init();
-    async_io::block_on(run_outer())?;
+    if config.print_grid {
+        return async_io::block_on(print_grid(&config));
+    }
+    if let Some(path) = &config.export_svg {
+        return async_io::block_on(export_svg(&config, path));
+    }
+    if config.check {
+        match async_io::block_on(check(&config)) {
+            Ok(()) => println!("OK"),
+            Err(e) => {
+                println!("FAIL {e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    async_io::block_on(run_outer(&config))?;
     Ok(())
 }
 
-async fn run_outer() -> std::io::Result<()> {
-    let mut signals = Signals::new([Signal::Term, Signal::Quit, Signal::Int])?;
-    run_retry()
+async fn run_outer(config: &Config) -> std::io::Result<()> {
+    let stop = config
+        .stop_signals
+        .iter()
+        .map(|&SignalName(signal)| signal)
+        .collect::<BTreeSet<_>>();
+    let reset = config.reset_signal.map(|SignalName(signal)| signal);
+    let mut signals = Signals::new(
+        stop.iter()
+            .copied()
+            .chain([Signal::Usr1, Signal::Usr2])
+            .chain(reset)
+            .collect::<BTreeSet<_>>(),
+    )?;
+    let (sender, requests) = async_channel::unbounded();
+    run_retry(config, &requests)
+        .race(async {
+            match &config.control_socket {
+                Some(path) => control::serve(path, sender.clone()).await,
+                None => futures_lite::future::pending().await,
+            }
+        })
         .race(async {
-            signals.try_next().await?;
+            while let Some(signal) = signals.try_next().await? {
+                if stop.contains(&signal) {
+                    break;
+                }
+                let command = match signal {
+                    _ if reset == Some(signal) => Command::Reset,
+                    Signal::Usr1 => Command::Toggle,
+                    Signal::Usr2 => Command::CycleBrightness,
+                    _ => continue,
+                };
+                let (reply, _) = async_channel::bounded(1);
+                let _ = sender.send(Request { command, reply }).await;
+            }
             Ok(())
         })
         .await?;
     Ok(())
 }
 
-async fn run_retry() -> std::io::Result<()> {
+async fn run_retry(config: &Config, requests: &Receiver<Request>) -> std::io::Result<()> {
     loop {
-        if let Err(e) = run().await {
-            log::error!("{e}")
+        match run(config, requests).await {
+            Ok(()) => return Ok(()),
+            Err(e) => log::error!("{e}"),
         }
         Timer::after(TRY_SLEEP).await;
     }
 }
 
-async fn run() -> std::io::Result<()> {
-    let mut tries = TRY_TIMES;
-    let (touchpad, device_id) = loop {
-        let mut touchpad = Touchpad::No;
-        let mut device_id: Option<String> = None;
-        let f = BufReader::new(File::open("/proc/bus/input/devices")?);
-        for line in f.lines() {
-            let line = line?;
-            loop {
-                match &touchpad {
-                    Touchpad::No => {
-                        if line.contains("Touchpad")
-                            && (line.contains(r#"Name="ASUE"#) || line.contains(r#"Name="ELAN"#))
-                        {
-                            touchpad = Touchpad::Yes;
-                        } else {
-                            break;
-                        }
-                    }
-                    Touchpad::Yes => {
-                        if line.contains("S: ") {
-                            device_id = Some(
-                                regex::Regex::new(".*i2c-(\\d+)/.*$")
-                                    .unwrap()
-                                    .replace(&line, "$1")
-                                    .to_string()
-                                    .replace('\n', ""),
-                            );
-                        }
-                        if line.contains("H: ") {
-                            touchpad = Touchpad::Some(
-                                line.split("event")
-                                    .nth(1)
-                                    .unwrap()
-                                    .split(' ')
-                                    .next()
-                                    .unwrap()
-                                    .to_string(),
-                            );
-                        } else {
-                            break;
-                        }
-                    }
-                    Touchpad::Some(_) => break,
+fn character(key: Key) -> Option<char> {
+    Some(match key {
+        Key::KEY_KP0 => '0',
+        Key::KEY_KP1 => '1',
+        Key::KEY_KP2 => '2',
+        Key::KEY_KP3 => '3',
+        Key::KEY_KP4 => '4',
+        Key::KEY_KP5 => '5',
+        Key::KEY_KP6 => '6',
+        Key::KEY_KP7 => '7',
+        Key::KEY_KP8 => '8',
+        Key::KEY_KP9 => '9',
+        Key::KEY_KPDOT => '.',
+        Key::KEY_KPCOMMA => ',',
+        Key::KEY_KPSLASH => '/',
+        Key::KEY_KPASTERISK => '*',
+        Key::KEY_KPMINUS => '-',
+        Key::KEY_KPPLUS => '+',
+        Key::KEY_KPEQUAL => '=',
+        Key::KEY_5 => '%',
+        _ => return None,
+    })
+}
+
+fn top_row_digit(key: Key) -> Option<Key> {
+    Some(match key {
+        Key::KEY_KP0 => Key::KEY_0,
+        Key::KEY_KP1 => Key::KEY_1,
+        Key::KEY_KP2 => Key::KEY_2,
+        Key::KEY_KP3 => Key::KEY_3,
+        Key::KEY_KP4 => Key::KEY_4,
+        Key::KEY_KP5 => Key::KEY_5,
+        Key::KEY_KP6 => Key::KEY_6,
+        Key::KEY_KP7 => Key::KEY_7,
+        Key::KEY_KP8 => Key::KEY_8,
+        Key::KEY_KP9 => Key::KEY_9,
+        _ => return None,
+    })
+}
+
+fn keys(config: &Config) -> AttributeSet<Key> {
+    let mut keys = AttributeSet::<Key>::new();
+    keys.insert(Key::KEY_NUMLOCK);
+    for key in config.calc_action.keys() {
+        keys.insert(*key);
+    }
+    for KeyName(key) in config.on_enable_key.iter().chain(&config.on_disable_key) {
+        keys.insert(*key);
+    }
+    for key in config
+        .characters
+        .values()
+        .chain(config.actions.values())
+        .chain(&config.equals_action)
+        .chain(&config.op_divide)
+        .chain(&config.op_multiply)
+        .chain(&config.op_minus)
+        .chain(&config.op_plus)
+        .flat_map(Action::keys)
+    {
+        keys.insert(*key);
+    }
+    if config.shift_all {
+        keys.insert(Key::KEY_LEFTSHIFT);
+    }
+    let profiles = config.profiles();
+    for KeyName(key) in profiles
+        .iter()
+        .flat_map(|profile| profile.layers.iter().flatten().flatten())
+        .chain(config.hold_keys.values())
+    {
+        match *key {
+            Key::KEY_5 => keys.insert(Key::KEY_LEFTSHIFT),
+            Key::KEY_KPDOT if config.decimal_separator == DecimalSeparator::Comma => {
+                keys.insert(Key::KEY_KPCOMMA)
+            }
+            Key::KEY_BACKSPACE if config.backspace_as_delete => keys.insert(Key::KEY_DELETE),
+            _ => {}
+        }
+        if let Some(digit) = top_row_digit(*key).filter(|_| config.top_row_digits) {
+            keys.insert(digit);
+        }
+        keys.insert(*key);
+    }
+    keys
+}
+
+fn keyboard(keys: &AttributeSet<Key>) -> std::io::Result<VirtualDevice> {
+    let builder = || Ok::<_, std::io::Error>(VirtualDeviceBuilder::new()?.name(KEYBOARD_NAME));
+    let mut accepted = AttributeSet::<Key>::new();
+    let mut device = builder()?;
+    for key in keys.iter() {
+        let mut single = AttributeSet::<Key>::new();
+        single.insert(key);
+        device = match device.with_keys(&single) {
+            Ok(device) => {
+                accepted.insert(key);
+                device
+            }
+            Err(e) => {
+                log::error!("uinput rejected {key:?}: {e}");
+                builder()?.with_keys(&accepted)?
+            }
+        };
+    }
+    device.build()
+}
+
+fn pointer() -> std::io::Result<VirtualDevice> {
+    let mut buttons = AttributeSet::<Key>::new();
+    buttons.insert(Key::BTN_LEFT);
+    let mut axes = AttributeSet::<RelativeAxisType>::new();
+    axes.insert(RelativeAxisType::REL_X);
+    axes.insert(RelativeAxisType::REL_Y);
+    axes.insert(RelativeAxisType::REL_WHEEL);
+    VirtualDeviceBuilder::new()?
+        .name("Asus Touchpad/Numpad Pointer")
+        .with_keys(&buttons)?
+        .with_relative_axes(&axes)?
+        .build()
+}
+
+fn brightness_message(brightness: u8) -> [u8; 13] {
+    [
+        0x05, 0x00, 0x3d, 0x03, 0x06, 0x00, 0x07, 0x00, 0x0d, 0x14, 0x03, brightness, 0xad,
+    ]
+}
+
+fn open_i2c(device_id: &str, address: u16) -> std::io::Result<LinuxI2CDevice> {
+    Ok(unsafe {
+        LinuxI2CDevice::force_new(Path::new("/dev").join(format!("i2c-{device_id}")), address)
+    }?)
+}
+
+trait I2cWrite {
+    fn write_message(&mut self, data: &[u8]) -> std::io::Result<u32>;
+}
+
+impl I2cWrite for LinuxI2CDevice {
+    fn write_message(&mut self, data: &[u8]) -> std::io::Result<u32> {
+        Ok(self.transfer(&mut [I2CMessage::write(data)])?)
+    }
+}
+
+struct NoI2c;
+
+impl I2cWrite for NoI2c {
+    fn write_message(&mut self, _: &[u8]) -> std::io::Result<u32> {
+        Ok(1)
+    }
+}
+
+async fn check(config: &Config) -> std::io::Result<()> {
+    let (touchpad, device_id) = detect::detect(config).await?;
+    let (_, touchpad) = open_touchpad(&touchpad)?;
+    let config = &models::select(touchpad.name().unwrap_or_default(), config);
+    if !config.i2c {
+        log::info!("i2c is off, not probing i2c-{device_id}");
+        return Ok(());
+    }
+    let mut device = open_i2c(&device_id, config.i2c_address)?;
+    let mut buf = [0];
+    let t = device.transfer(&mut [I2CMessage::read(&mut buf)])?;
+    if t != 1 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn has_position(device: &Device) -> bool {
+    device.supported_absolute_axes().is_some_and(|axes| {
+        axes.contains(AbsoluteAxisType::ABS_MT_POSITION_X)
+            && axes.contains(AbsoluteAxisType::ABS_MT_POSITION_Y)
+    })
+}
+
+fn siblings(touchpad: &str) -> std::io::Result<Vec<String>> {
+    let parent = Path::new("/sys/class/input")
+        .join(format!("event{touchpad}"))
+        .join("device/device/input");
+    let mut siblings = Vec::new();
+    for input in std::fs::read_dir(parent)? {
+        for node in std::fs::read_dir(input?.path())? {
+            let name = node?.file_name();
+            if let Some(sibling) = name.to_str().and_then(|name| name.strip_prefix("event")) {
+                if sibling != touchpad {
+                    siblings.push(sibling.to_string());
                 }
             }
         }
-        match (touchpad, device_id) {
-            (Touchpad::Some(touchpad), Some(device_id)) => break (touchpad, device_id),
-            _ => log::error!("bwaaa"),
+    }
+    let number = |node: &str| node.parse::<u32>().ok();
+    siblings.sort_by_key(|sibling| {
+        number(sibling)
+            .zip(number(touchpad))
+            .map_or(u32::MAX, |(a, b)| a.abs_diff(b))
+    });
+    Ok(siblings)
+}
+
+fn open_touchpad(touchpad: &str) -> std::io::Result<(PathBuf, Device)> {
+    let path = |touchpad: &str| Path::new("/dev/input").join(format!("event{touchpad}"));
+    let device = Device::open(path(touchpad))?;
+    if has_position(&device) {
+        return Ok((path(touchpad), device));
+    }
+    log::warn!("event{touchpad} reports no touch position, trying its siblings");
+    for sibling in siblings(touchpad)? {
+        match Device::open(path(&sibling)) {
+            Ok(device) if has_position(&device) => return Ok((path(&sibling), device)),
+            Ok(_) => {}
+            Err(e) => log::warn!("event{sibling}: {e}"),
+        }
+    }
+    log::warn!("no sibling reports touch position, staying on event{touchpad}");
+    Ok((path(touchpad), device))
+}
+
+fn find_device(matches: impl Fn(&Device) -> bool) -> std::io::Result<Option<(PathBuf, Device)>> {
+    for (path, device) in evdev::enumerate() {
+        if device.name() != Some(KEYBOARD_NAME) && matches(&device) {
+            fcntl(device.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            return Ok(Some((path, device)));
         }
-        tries -= 1;
-        if tries == 0 {
-            return Err(std::io::ErrorKind::TimedOut.into());
+    }
+    Ok(None)
+}
+
+fn tablet_switch() -> std::io::Result<Option<(PathBuf, Device)>> {
+    find_device(|device| {
+        device
+            .supported_switches()
+            .is_some_and(|switches| switches.contains(SwitchType::SW_TABLET_MODE))
+    })
+}
+
+fn master_keyboard(key: Key) -> std::io::Result<Option<(PathBuf, Device)>> {
+    find_device(|device| {
+        device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(key))
+    })
+}
+
+fn log_capabilities(touchpad: &Device) -> std::io::Result<()> {
+    log::debug!("events {:?}", touchpad.supported_events());
+    if let Some(keys) = touchpad.supported_keys() {
+        log::debug!("keys {:?}", keys.iter().collect::<Vec<_>>());
+    }
+    if let Some(axes) = touchpad.supported_absolute_axes() {
+        let abs = touchpad.get_abs_state()?;
+        for axis in axes.iter() {
+            let info = abs[axis.0 as usize];
+            log::debug!(
+                "{axis:?} {}-{} resolution {}",
+                info.minimum,
+                info.maximum,
+                info.resolution
+            );
         }
-        Timer::after(TRY_SLEEP).await;
+    }
+    Ok(())
+}
+
+fn position_axes(touchpad: &Device, config: &Config) -> [AbsoluteAxisType; 2] {
+    let mt = match config.position_axes {
+        PositionAxes::Auto => has_position(touchpad),
+        PositionAxes::Mt => true,
+        PositionAxes::St => false,
     };
-    log::info!("touchpad {touchpad}");
-    log::info!("device_id {device_id}");
-    let touchpad = Device::open(Path::new("/dev/input").join(format!("event{touchpad}")))?;
-    fcntl(touchpad.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    if mt {
+        [
+            AbsoluteAxisType::ABS_MT_POSITION_X,
+            AbsoluteAxisType::ABS_MT_POSITION_Y,
+        ]
+    } else {
+        [AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y]
+    }
+}
+
+fn bounds(
+    touchpad: &Device,
+    [x, y]: [AbsoluteAxisType; 2],
+    config: &Config,
+) -> std::io::Result<(i32, i32, i32, i32)> {
     let abs = touchpad.get_abs_state()?;
-    let absx = abs[AbsoluteAxisType::ABS_X.0 as usize];
+    let absx = abs[x.0 as usize];
     let (minx, maxx) = (absx.minimum, absx.maximum);
-    let absy = abs[AbsoluteAxisType::ABS_Y.0 as usize];
+    let absy = abs[y.0 as usize];
     let (miny, maxy) = (absy.minimum, absy.maximum);
+    Ok(if config.transpose {
+        (miny, maxy, minx, maxx)
+    } else {
+        (minx, maxx, miny, maxy)
+    })
+}
+
+fn numlock_corner(config: &Config) -> (Percent, Percent) {
+    let (width, height) = if config.fullpad {
+        (Percent(3), Percent(5))
+    } else {
+        (Percent(5), Percent(9))
+    };
+    (
+        config.numlock_width.unwrap_or(width),
+        config.numlock_height.unwrap_or(height),
+    )
+}
+
+fn calculator_corner(config: &Config) -> (Percent, Percent) {
+    let (width, height) = if config.fullpad {
+        (Percent(3), Percent(4))
+    } else {
+        (Percent(6), Percent(7))
+    };
+    (
+        config.calculator_width.unwrap_or(width),
+        config.calculator_height.unwrap_or(height),
+    )
+}
+
+fn cells(from: i32, to: i32, cell: impl Fn(i32) -> Option<usize>) -> Vec<(usize, i32, i32)> {
+    let mut cells: Vec<(usize, i32, i32)> = Vec::new();
+    for at in from..=to {
+        match (cell(at), cells.last_mut()) {
+            (Some(c), Some((last, _, end))) if *last == c && *end == at - 1 => *end = at,
+            (Some(c), _) => cells.push((c, at, at)),
+            (None, _) => {}
+        }
+    }
+    cells
+}
+
+async fn detected_geometry(config: &Config) -> std::io::Result<Geometry> {
+    let (touchpad, _) = detect::detect(config).await?;
+    let touchpad = Device::open(Path::new("/dev/input").join(format!("event{touchpad}")))?;
+    let config = &models::select(touchpad.name().unwrap_or_default(), config);
+    let axes = position_axes(&touchpad, config);
+    Ok(Geometry::new(bounds(&touchpad, axes, config)?, config))
+}
+
+async fn print_grid(config: &Config) -> std::io::Result<()> {
+    let geometry = detected_geometry(config).await?;
+    let (minx, maxx, miny, maxy) = (geometry.minx, geometry.maxx, geometry.miny, geometry.maxy);
+    let (width, height) = (geometry.width(), geometry.height());
+    println!("touchpad x {minx}-{maxx} y {miny}-{maxy}");
+    println!(
+        "grid x {}-{} y {}-{}",
+        geometry.left_np(),
+        geometry.right_np(),
+        geometry.top_np(),
+        geometry.bottom_np()
+    );
+    for (column, from, to) in cells(minx, maxx, |x| geometry.column(x)) {
+        println!("column {column} x {from}-{to}");
+    }
+    for (row, from, to) in cells(miny, maxy, |y| geometry.row(y)) {
+        println!("row {row} y {from}-{to}");
+        if config.row_offsets.get(row).is_some() {
+            let geometry = geometry.in_row(row);
+            for (column, from, to) in cells(minx, maxx, |x| geometry.column(x)) {
+                println!("row {row} column {column} x {from}-{to}");
+            }
+        }
+    }
+    let (corner_width, corner_height) = numlock_corner(config);
+    println!(
+        "numlock x {}-{maxx} y {miny}-{}",
+        maxx - corner_width * width,
+        miny + corner_height * height
+    );
+    let (corner_width, corner_height) = calculator_corner(config);
+    println!(
+        "calculator x {minx}-{} y {miny}-{}",
+        minx + corner_width * width,
+        miny + corner_height * height
+    );
+    Ok(())
+}
+
+async fn export_svg(config: &Config, path: &Path) -> std::io::Result<()> {
+    let geometry = detected_geometry(config).await?;
+    let (minx, maxx, miny, maxy) = (geometry.minx, geometry.maxx, geometry.miny, geometry.maxy);
+    let (width, height) = (geometry.width(), geometry.height());
+    let mut svg = Svg::new((minx, maxx, miny, maxy));
+    let (corner_width, corner_height) = numlock_corner(config);
+    let numlock = (
+        maxx - corner_width * width,
+        maxx,
+        miny,
+        miny + corner_height * height,
+    );
+    svg.rect(numlock, "lightgray");
+    let (corner_width, corner_height) = calculator_corner(config);
+    let calculator = (
+        minx,
+        minx + corner_width * width,
+        miny,
+        miny + corner_height * height,
+    );
+    svg.rect(calculator, "lightgray");
+    let rows = cells(miny, maxy, |y| geometry.row(y));
+    for &(row, y0, y1) in &rows {
+        let geometry = geometry.in_row(row);
+        for (column, x0, x1) in cells(minx, maxx, |x| geometry.column(x)) {
+            let KeyName(key) = config.layers[0][row][column];
+            let label = character(key).map_or_else(
+                || {
+                    let name = format!("{key:?}");
+                    name.strip_prefix("KEY_").unwrap_or(&name).to_string()
+                },
+                String::from,
+            );
+            svg.rect((x0, x1, y0, y1), "none");
+            svg.label((x0, x1, y0, y1), &label);
+        }
+    }
+    std::fs::write(path, svg.finish())?;
+    log::info!("wrote {}", path.display());
+    Ok(())
+}
+
+fn lap(mark: &mut Instant, phase: &str) {
+    log::debug!("{phase} took {:?}", mark.elapsed());
+    *mark = Instant::now();
+}
+
+async fn run(config: &Config, requests: &Receiver<Request>) -> std::io::Result<()> {
+    let started = Instant::now();
+    let mut mark = started;
+    let (touchpad, device_id) = detect::detect(config).await?;
+    lap(&mut mark, "detection");
+    log::info!("touchpad {touchpad}");
+    log::info!("device_id {device_id}");
+    let (path, touchpad) = open_touchpad(&touchpad)?;
+    lap(&mut mark, "opening the event device");
+    log::info!("device {}", path.display());
+    let config = &models::select(touchpad.name().unwrap_or_default(), config);
+    if log::log_enabled!(log::Level::Debug) {
+        log_capabilities(&touchpad)?;
+    }
+    fcntl(touchpad.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    let axes = position_axes(&touchpad, config);
+    log::info!("axes {:?} {:?}", axes[0], axes[1]);
+    let (minx, maxx, miny, maxy) = bounds(&touchpad, axes, config)?;
+    lap(&mut mark, "reading the abs state");
     log::info!("x {minx}-{maxx}  y {miny}-{maxy}");
-    let percentage_key = Key::KEY_5;
-    let mut keys = AttributeSet::<Key>::new();
-    keys.insert(Key::KEY_LEFTSHIFT);
-    keys.insert(Key::KEY_NUMLOCK);
-    keys.insert(Key::KEY_CALC);
-    for key in KEYS.into_iter().flatten() {
-        keys.insert(key);
-    }
-    if percentage_key != Key::KEY_5 {
-        keys.insert(percentage_key);
-    }
-    let udev = VirtualDeviceBuilder::new()?
-        .name("Asus Touchpad/Numpad")
-        .with_keys(&keys)?
-        .build()?;
-    let device = unsafe {
-        LinuxI2CDevice::force_new(Path::new("/dev").join(format!("i2c-{device_id}")), 0x15)
-    }?;
+    let finger_key = match config.finger_event {
+        FingerEvent::Auto
+            if !touchpad
+                .supported_keys()
+                .is_some_and(|keys| keys.contains(Key::BTN_TOOL_FINGER)) =>
+        {
+            Key::BTN_TOUCH
+        }
+        FingerEvent::Auto | FingerEvent::ToolFinger => Key::BTN_TOOL_FINGER,
+        FingerEvent::Touch => Key::BTN_TOUCH,
+    };
+    log::info!("finger {finger_key:?}");
+    let master = match &config.master_key {
+        Some(KeyName(key)) => match master_keyboard(*key) {
+            Ok(Some((path, keyboard))) => {
+                log::info!("master key {key:?} on {}", path.display());
+                Some(Async::new(keyboard)?)
+            }
+            Ok(None) => {
+                log::warn!("no keyboard with {key:?} found");
+                None
+            }
+            Err(e) => {
+                log::warn!("{e}");
+                None
+            }
+        },
+        None => None,
+    };
+    let mut udev: Box<dyn KeySink> = match &config.uinput_path {
+        Some(path) => {
+            log::info!("uinput {}", path.display());
+            Box::new(RawDevice::open(path)?)
+        }
+        None if config.wayland => {
+            log::info!("wayland virtual keyboard");
+            wayland::sink(config.xkb_numlock)?
+        }
+        None if config.keyboards > 1 => {
+            let keys = keys(config);
+            let keyboards = (0..config.keyboards)
+                .map(|_| Ok(Box::new(keyboard(&keys)?) as Box<dyn KeySink>))
+                .collect::<std::io::Result<_>>()?;
+            log::info!("{} virtual keyboards", config.keyboards);
+            Box::new(Tee(keyboards))
+        }
+        None => Box::new(keyboard(&keys(config))?),
+    };
+    if config.tee_log {
+        udev = Box::new(Tee(vec![udev, Box::new(LogSink)]));
+    }
+    let pointer = if config.none_action == NoneAction::PassthroughTap
+        || config.scroll_strip.is_some()
+        || config.button_mode
+    {
+        Some(pointer()?)
+    } else {
+        None
+    };
+    lap(&mut mark, "building the virtual devices");
+    let device: Box<dyn I2cWrite> = if config.i2c {
+        Box::new(open_i2c(&device_id, config.i2c_address)?)
+    } else {
+        Box::new(NoI2c)
+    };
     let touchpad = Async::new(touchpad)?;
+    let state = match &config.state_file {
+        Some(path) => State::load(path).unwrap_or_else(|e| {
+            log::warn!("{e}");
+            State::default()
+        }),
+        None => State::default(),
+    };
     let mut context = Context {
-        no_touch: NoTouch {
+        no_touch: NoTouch::new(
             device,
+            device_id,
             udev,
-            minx,
-            maxx,
-            miny,
-            maxy,
-            x: 0,
-            y: 0,
-            pressed: None,
-            numlock: false,
-        },
+            pointer,
+            (minx, maxx, miny, maxy),
+            axes,
+            finger_key,
+            state.brightness.unwrap_or(config.brightness),
+            config,
+        ),
         touchpad,
+        switch: None,
+        master,
     };
-    context.run().await?;
+    if config.auto_rotate {
+        match tablet_switch() {
+            Ok(Some((path, switch))) => {
+                log::info!("tablet mode switch {}", path.display());
+                context.no_touch.tablet = switch
+                    .get_switch_state()?
+                    .contains(SwitchType::SW_TABLET_MODE);
+                context.switch = Some(Async::new(switch)?);
+            }
+            Ok(None) => log::warn!("no tablet mode switch found"),
+            Err(e) => log::warn!("{e}"),
+        }
+    }
+    context.no_touch.restore_layer(state.profile, state.layer);
+    if config.reset_on_start {
+        context.no_touch.deactivate()?;
+    }
+    if state.numlock && !config.learn {
+        context.no_touch.set_numlock(true)?;
+        lap(&mut mark, "first activation");
+    }
+    log::debug!("startup took {:?}", started.elapsed());
+    if config.learn {
+        context.learn().await?;
+    } else {
+        context.run(requests).await?;
+    }
     drop(context);
     log::info!("stopped");
     Ok(())
 }
 
+#[derive(Default)]
+struct Slots {
+    slot: i32,
+    contacts: BTreeMap<i32, (i32, i32)>,
+    landed: Vec<i32>,
+    lifted: Vec<i32>,
+    gate: Option<i32>,
+    typing: Option<i32>,
+}
+
+#[derive(Default)]
+struct Latency {
+    count: u32,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl Latency {
+    fn add(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = self.max.max(latency);
+    }
+}
+
+struct Fade {
+    from: u8,
+    to: u8,
+    step: u8,
+    next: Instant,
+}
+
+struct I2cRetry {
+    data: Vec<u8>,
+    level: Option<u8>,
+    what: &'static str,
+    retries: u32,
+    next: Instant,
+}
+
 struct NoTouch {
-    device: LinuxI2CDevice,
-    udev: VirtualDevice,
+    device: Box<dyn I2cWrite>,
+    device_id: String,
+    udev: Box<dyn KeySink>,
+    pointer: Option<VirtualDevice>,
     minx: i32,
     maxx: i32,
     miny: i32,
     maxy: i32,
     x: i32,
     y: i32,
+    axes: [AbsoluteAxisType; 2],
+    finger_key: Key,
+    tools: u8,
+    gesture: bool,
+    down: bool,
+    new_contact: bool,
+    lifting: Option<Instant>,
+    landed: Option<SystemTime>,
+    batch: Option<Vec<InputEvent>>,
+    latency: Latency,
+    activated: Option<Instant>,
+    regrab: bool,
+    recalibrate_since: Option<Instant>,
     pressed: Option<Key>,
+    pressed_cell: Option<(usize, usize)>,
+    pressed_at: (i32, i32),
+    finger: Option<bool>,
     numlock: bool,
+    xkb_numlock: bool,
+    announced: bool,
+    last_brightness: u8,
+    layer: usize,
+    activate_sequence: Option<Vec<u8>>,
+    deactivate_sequence: Option<Vec<u8>>,
+    learn: Option<Learn>,
+    holding: Option<(Key, Instant)>,
+    tracking: bool,
+    last_release: Option<Instant>,
+    last_toggle: Option<Instant>,
+    toggle_armed: Option<Instant>,
+    heatmap: [[u32; COLS]; ROWS],
+    slots: Slots,
+    scrolling: Option<i32>,
+    tablet: bool,
+    last_on_press: Option<Instant>,
+    children: Vec<std::process::Child>,
+    calculator_tap: Option<Instant>,
+    percent_tap: Option<Instant>,
+    bell: Option<Device>,
+    bell_until: Option<Instant>,
+    profiles: Vec<Config>,
+    profile: usize,
+    level: u8,
+    last_touch: Instant,
+    dimmed: bool,
+    fade: Option<Fade>,
+    i2c_retry: Option<I2cRetry>,
+    on_battery: bool,
+    next_power_poll: Instant,
+    disabled: bool,
+    switched_off: bool,
+    next_disable_poll: Instant,
+    locked: bool,
+    next_lock_poll: Instant,
+    lock_check: Option<std::process::Child>,
+    session: Option<u32>,
+    resume_vt: Option<u32>,
+    next_session_poll: Instant,
+    config: Config,
 }
 
 impl Drop for NoTouch {
     fn drop(&mut self) {
+        if self.config.heatmap {
+            log::info!("presses per cell:");
+            for row in &self.heatmap {
+                log::info!("{}", row.map(|count| format!("{count:>6}")).concat());
+            }
+        }
+        if let Some(min) = self.latency.min {
+            log::info!(
+                "press latency min {min:?} avg {:?} max {:?} over {} presses",
+                self.latency.total / self.latency.count,
+                self.latency.max,
+                self.latency.count
+            );
+        }
         if let Err(e) = self.deactivate() {
             log::error!("{e}")
         }
     }
 }
 
-fn non_neg_sub(a: i32, b: i32) -> Option<i32> {
-    let x = a.checked_sub(b)?;
-    (x >= 0).then_some(x)
+fn due(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| deadline <= Instant::now())
 }
 
 impl NoTouch {
-    fn activate(&mut self) -> std::io::Result<()> {
-        let mut msgs = [I2CMessage::write(&[
-            0x05, 0x00, 0x3d, 0x03, 0x06, 0x00, 0x07, 0x00, 0x0d, 0x14, 0x03, 0x01, 0xad,
-        ])];
-        let t = self.device.transfer(&mut msgs)?;
-        if t != 1 {
-            log::error!("activate write failed");
-            return Err(std::io::ErrorKind::WriteZero.into());
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        device: Box<dyn I2cWrite>,
+        device_id: String,
+        udev: Box<dyn KeySink>,
+        pointer: Option<VirtualDevice>,
+        (minx, maxx, miny, maxy): (i32, i32, i32, i32),
+        axes: [AbsoluteAxisType; 2],
+        finger_key: Key,
+        last_brightness: u8,
+        config: &Config,
+    ) -> Self {
+        Self {
+            device,
+            device_id,
+            udev,
+            pointer,
+            minx,
+            maxx,
+            miny,
+            maxy,
+            x: 0,
+            y: 0,
+            axes,
+            finger_key,
+            tools: 0,
+            gesture: false,
+            down: false,
+            new_contact: false,
+            lifting: None,
+            landed: None,
+            batch: None,
+            latency: Latency::default(),
+            activated: None,
+            regrab: false,
+            recalibrate_since: None,
+            pressed: None,
+            pressed_cell: None,
+            pressed_at: (0, 0),
+            finger: None,
+            numlock: false,
+            xkb_numlock: false,
+            announced: false,
+            last_brightness,
+            layer: 0,
+            activate_sequence: config.activate_sequence(),
+            deactivate_sequence: config.deactivate_sequence(),
+            learn: None,
+            holding: None,
+            tracking: false,
+            last_release: None,
+            last_toggle: None,
+            toggle_armed: None,
+            heatmap: [[0; COLS]; ROWS],
+            slots: Slots::default(),
+            scrolling: None,
+            tablet: false,
+            last_on_press: None,
+            children: Vec::new(),
+            calculator_tap: None,
+            percent_tap: None,
+            bell: None,
+            bell_until: None,
+            profiles: config.profiles(),
+            profile: 0,
+            level: 0,
+            last_touch: Instant::now(),
+            dimmed: false,
+            fade: None,
+            i2c_retry: None,
+            on_battery: false,
+            next_power_poll: Instant::now(),
+            disabled: false,
+            switched_off: false,
+            next_disable_poll: Instant::now(),
+            locked: false,
+            next_lock_poll: Instant::now(),
+            lock_check: None,
+            session: None,
+            resume_vt: None,
+            next_session_poll: Instant::now(),
+            config: config.clone(),
         }
-        self.udev
-            .emit(&[InputEvent::new(EventType::KEY, Key::KEY_NUMLOCK.code(), 1)])?;
-        Ok(())
     }
 
-    fn deactivate(&mut self) -> std::io::Result<()> {
-        self.udev
-            .emit(&[InputEvent::new(EventType::KEY, Key::KEY_NUMLOCK.code(), 0)])?;
-        let mut msgs = [I2CMessage::write(&[
-            0x05, 0x00, 0x3d, 0x03, 0x06, 0x00, 0x07, 0x00, 0x0d, 0x14, 0x03, 0x00, 0xad,
-        ])];
-        let t = self.device.transfer(&mut msgs)?;
-        if t != 1 {
-            log::error!("deactivate write failed");
-            return Err(std::io::ErrorKind::WriteZero.into());
-        }
+    fn reopen_i2c(&mut self) -> std::io::Result<()> {
+        self.device = Box::new(open_i2c(&self.device_id, self.config.i2c_address)?);
         Ok(())
     }
 
-    fn release(&mut self) -> std::io::Result<()> {
-        if let Some(button) = self.pressed.take() {
-            self.udev.emit(&[
-                InputEvent::new(EventType::KEY, Key::KEY_LEFTSHIFT.code(), 0),
-                InputEvent::new(EventType::KEY, button.code(), 0),
-            ])?
-        }
-        Ok(())
+    fn transfer(&mut self, data: &[u8]) -> std::io::Result<u32> {
+        self.device.write_message(data)
     }
 
-    fn try_calculator(&mut self) -> std::io::Result<()> {
-        self.udev.emit(&[
-            InputEvent::new(EventType::KEY, Key::KEY_CALC.code(), 1),
-            InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0),
-            InputEvent::new(EventType::KEY, Key::KEY_CALC.code(), 0),
-        ])
+    fn write(&mut self, data: &[u8]) -> std::io::Result<u32> {
+        let t = match self.transfer(data) {
+            Err(e)
+                if e.raw_os_error() == Some(Errno::ENXIO as i32)
+                    || e.raw_os_error() == Some(Errno::EIO as i32) =>
+            {
+                log::warn!("{e}, reopening the i2c device");
+                self.reopen_i2c()?;
+                self.transfer(data)?
+            }
+            result => result?,
+        };
+        log::debug!(
+            "i2c write {} -> {t}",
+            data.iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        Ok(t)
     }
 
-    fn calculator(&mut self) {
-        if let Err(e) = self.try_calculator() {
-            log::error!("{e}")
+    fn write_brightness(&mut self, brightness: u8) -> std::io::Result<u32> {
+        if self.activate_sequence.is_some() || self.deactivate_sequence.is_some() {
+            log::debug!("custom i2c sequences, not writing brightness {brightness:#04x}");
+            self.level = brightness;
+            return Ok(1);
         }
+        let t = self.write(&brightness_message(brightness))?;
+        self.level = brightness;
+        Ok(t)
     }
 
-    fn width(&self) -> i32 {
-        self.maxx - self.minx
+    fn fade_step(&mut self) -> std::io::Result<()> {
+        let Some(fade) = &mut self.fade else {
+            return Ok(());
+        };
+        fade.step += 1;
+        let steps = self.config.fade_steps;
+        let level = i32::from(fade.from)
+            + (i32::from(fade.to) - i32::from(fade.from)) * i32::from(fade.step) / i32::from(steps);
+        if fade.step >= steps {
+            self.fade = None;
+        } else {
+            fade.next = Instant::now() + Duration::from_millis(self.config.fade_step_ms);
+        }
+        let t = self.write_brightness(level as u8)?;
+        if t != 1 {
+            log::error!("fade write failed");
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        Ok(())
     }
 
-    fn height(&self) -> i32 {
-        self.maxy - self.miny
+    fn start_fade(&mut self, to: u8) -> std::io::Result<()> {
+        self.i2c_retry = None;
+        self.fade = Some(Fade {
+            from: self.level,
+            to,
+            step: 0,
+            next: Instant::now(),
+        });
+        self.fade_step()
     }
 
-    fn left_percent(&self) -> Percent {
-        Percent::div(self.x - self.minx, self.width())
+    fn write_retrying(
+        &mut self,
+        data: Vec<u8>,
+        level: Option<u8>,
+        what: &'static str,
+    ) -> std::io::Result<()> {
+        self.retry_write(I2cRetry {
+            data,
+            level,
+            what,
+            retries: self.config.i2c_retries.min(MAX_I2C_RETRIES),
+            next: Instant::now(),
+        })
     }
 
-    fn right_percent(&self) -> Percent {
-        Percent::div(self.maxx - self.x, self.width())
+    fn retry_write(&mut self, mut retry: I2cRetry) -> std::io::Result<()> {
+        self.i2c_retry = None;
+        match self.write(&retry.data) {
+            Ok(1) => {
+                if let Some(level) = retry.level {
+                    self.level = level;
+                }
+                return Ok(());
+            }
+            Ok(_) if retry.retries == 0 => {
+                log::error!("{} write failed", retry.what);
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            Err(e) if retry.retries == 0 => return Err(e),
+            Ok(t) => log::warn!("i2c write -> {t}, retrying"),
+            Err(e) => log::warn!("{e}, retrying"),
+        }
+        retry.retries -= 1;
+        retry.next = Instant::now() + Duration::from_millis(self.config.i2c_retry_ms);
+        self.i2c_retry = Some(retry);
+        Ok(())
     }
 
-    fn top_percent(&self) -> Percent {
-        Percent::div(self.y - self.miny, self.height())
+    fn activate(&mut self) -> std::io::Result<()> {
+        if self.activate_sequence.is_none() && self.config.fade_steps > 0 {
+            self.start_fade(self.effective_brightness())?;
+        } else {
+            match self.activate_sequence.clone() {
+                Some(sequence) => self.write_retrying(sequence, None, "activate")?,
+                None => {
+                    let brightness = self.effective_brightness();
+                    let data = brightness_message(brightness).to_vec();
+                    self.write_retrying(data, Some(brightness), "activate")?
+                }
+            }
+        }
+        if self.config.regrab_after_activate_ms.is_some() {
+            self.activated = Some(Instant::now());
+        }
+        self.numlock_key(true)
     }
 
-    fn _bottom_percent(&self) -> Percent {
-        Percent::div(self.maxy - self.y, self.height())
-    }
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        match &mut self.batch {
+            Some(batch) => {
+                for event in events {
+                    let frame = batch
+                        .iter()
+                        .rposition(|e| e.event_type() == EventType::SYNCHRONIZATION)
+                        .map_or(0, |syn| syn + 1);
+                    if batch[frame..]
+                        .iter()
+                        .any(|e| e.event_type() == event.event_type() && e.code() == event.code())
+                    {
+                        batch.push(InputEvent::new(
+                            EventType::SYNCHRONIZATION,
+                            Synchronization::SYN_REPORT.0,
+                            0,
+                        ));
+                    }
+                    batch.push(*event);
+                }
+                Ok(())
+            }
+            None => self.udev.emit(events),
+        }
+    }
 
-    fn numlock_hit(&self) -> bool {
-        self.right_percent() < Percent(5) && self.top_percent() < Percent(9)
+    fn tap(&mut self, key: Key) -> std::io::Result<()> {
+        self.emit(&[InputEvent::new(EventType::KEY, key.code(), 1)])?;
+        self.emit(&[InputEvent::new(EventType::KEY, key.code(), 0)])
     }
 
-    fn calculator_hit(&self) -> bool {
-        self.left_percent() < Percent(6) && self.top_percent() < Percent(7)
+    fn numlock_key(&mut self, on: bool) -> std::io::Result<()> {
+        if !self.config.xkb_numlock {
+            self.emit(&[InputEvent::new(
+                EventType::KEY,
+                Key::KEY_NUMLOCK.code(),
+                on.into(),
+            )])?;
+        } else if self.xkb_numlock != on {
+            self.tap(Key::KEY_NUMLOCK)?;
+            self.xkb_numlock = on;
+        }
+        if self.announced != on {
+            self.announced = on;
+            let key = if on {
+                self.config.on_enable_key
+            } else {
+                self.config.on_disable_key
+            };
+            if let Some(KeyName(key)) = key {
+                self.tap(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fade_out(&mut self) -> std::io::Result<()> {
+        if self.deactivate_sequence.is_some() || self.config.fade_steps == 0 {
+            return self.deactivate();
+        }
+        self.numlock_key(false)?;
+        self.start_fade(0x00)
+    }
+
+    fn deactivate(&mut self) -> std::io::Result<()> {
+        self.fade = None;
+        self.numlock_key(false)?;
+        match self.deactivate_sequence.clone() {
+            Some(sequence) => self.write_retrying(sequence, None, "deactivate"),
+            None => {
+                let data = brightness_message(0x00).to_vec();
+                self.write_retrying(data, Some(0x00), "deactivate")
+            }
+        }
+    }
+
+    fn effective_brightness(&self) -> u8 {
+        if self.locked && self.config.locked_off {
+            0x00
+        } else if self.dimmed {
+            self.config.dim_brightness.min(self.last_brightness)
+        } else if self.on_battery && self.config.on_battery == OnBattery::Dim {
+            MIN_BRIGHTNESS
+        } else {
+            self.last_brightness
+        }
+    }
+
+    fn apply_brightness(&mut self) -> std::io::Result<()> {
+        if self.numlock {
+            self.fade = None;
+            let t = self.write_brightness(self.effective_brightness())?;
+            if t != 1 {
+                log::error!("brightness write failed");
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn set_brightness(&mut self, brightness: u8) -> std::io::Result<()> {
+        self.last_brightness = brightness;
+        self.save_state();
+        self.apply_brightness()
     }
 
-    fn left_np(&self) -> i32 {
-        self.minx + LEFT_OFFSET * self.width()
+    fn save_state(&self) {
+        if let Some(path) = &self.config.state_file {
+            let state = State {
+                numlock: self.numlock,
+                brightness: Some(self.last_brightness),
+                profile: self.profile,
+                layer: self.layer,
+            };
+            if let Err(e) = state.save(path) {
+                log::warn!("{}: {e}", path.display());
+            }
+        }
     }
 
-    fn right_np(&self) -> i32 {
-        self.maxx - RIGHT_OFFSET * self.width()
+    fn poll_power(&mut self) -> std::io::Result<()> {
+        self.next_power_poll = Instant::now() + POWER_POLL;
+        let on_battery = power::on_battery()?;
+        if self.on_battery != on_battery {
+            log::info!("on battery {on_battery}");
+            self.on_battery = on_battery;
+            self.apply_brightness()?;
+        }
+        Ok(())
     }
 
-    fn top_np(&self) -> i32 {
-        self.miny + TOP_OFFSET * self.height()
+    fn poll_disable(&mut self) -> std::io::Result<()> {
+        self.next_disable_poll = Instant::now() + DISABLE_POLL;
+        let disabled = self
+            .config
+            .disable_file
+            .as_ref()
+            .is_some_and(|path| path.exists());
+        if self.disabled != disabled {
+            log::info!("disabled {disabled}");
+            if disabled {
+                self.set_numlock(false)?;
+            }
+            self.disabled = disabled;
+        }
+        Ok(())
     }
 
-    fn bottom_np(&self) -> i32 {
-        self.maxy - BOTTOM_OFFSET * self.height()
+    fn with_master(&mut self, keyboard: &mut Device) -> std::io::Result<()> {
+        let Some(KeyName(key)) = self.config.master_key else {
+            return Ok(());
+        };
+        for e in keyboard.fetch_events()? {
+            if e.kind() == InputEventKind::Key(key) && e.value() == 1 {
+                let switched_off = !self.switched_off;
+                log::info!("switched off {switched_off}");
+                if switched_off {
+                    self.set_numlock(false)?;
+                }
+                self.switched_off = switched_off;
+            }
+        }
+        Ok(())
     }
 
-    fn width_np(&self) -> i32 {
-        self.right_np() - self.left_np()
+    fn poll_lock(&mut self) -> std::io::Result<()> {
+        self.next_lock_poll = Instant::now() + LOCK_POLL;
+        let Some(command) = self.config.locked_command.clone() else {
+            return Ok(());
+        };
+        let Some(check) = &mut self.lock_check else {
+            let mut sh = std::process::Command::new("sh");
+            sh.arg("-c")
+                .arg(command)
+                .stdout(std::process::Stdio::null());
+            self.lock_check = self.spawn_child(&mut sh);
+            self.next_lock_poll = Instant::now() + LOCK_CHECK_POLL;
+            return Ok(());
+        };
+        let Some(status) = check.try_wait()? else {
+            self.next_lock_poll = Instant::now() + LOCK_CHECK_POLL;
+            return Ok(());
+        };
+        self.lock_check = None;
+        let locked = status.success();
+        if self.locked != locked {
+            log::info!("locked {locked}");
+            self.locked = locked;
+            if locked {
+                self.release()?;
+            }
+            self.apply_brightness()?;
+        }
+        Ok(())
     }
 
-    fn height_np(&self) -> i32 {
-        self.bottom_np() - self.top_np()
+    fn poll_session(&mut self) -> std::io::Result<()> {
+        self.next_session_poll = Instant::now() + SESSION_POLL;
+        let vt = vt::active()?;
+        let Some(previous) = self.session.replace(vt).filter(|session| *session != vt) else {
+            return Ok(());
+        };
+        log::info!("vt {vt} active");
+        if self.config.release_on_session_change {
+            self.release()?;
+        }
+        if self.config.keep_across_vt {
+            if self.numlock {
+                self.resume_vt = Some(previous);
+                self.set_numlock(false)?;
+            } else if self.resume_vt == Some(vt) {
+                self.resume_vt = None;
+                self.set_numlock(true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn wake(&mut self) -> std::io::Result<()> {
+        self.last_touch = Instant::now();
+        if std::mem::take(&mut self.dimmed) {
+            self.apply_brightness()?;
+        }
+        Ok(())
+    }
+
+    fn set_numlock(&mut self, numlock: bool) -> std::io::Result<()> {
+        if numlock && self.on_battery && self.config.on_battery == OnBattery::Disable {
+            log::info!("on battery, not activating");
+            return Ok(());
+        }
+        if numlock && self.disabled {
+            log::info!("disabled, not activating");
+            return Ok(());
+        }
+        if numlock && self.switched_off {
+            log::info!("switched off, not activating");
+            return Ok(());
+        }
+        if self.numlock != numlock {
+            self.release()?;
+            self.numlock = numlock;
+            self.last_touch = Instant::now();
+            self.dimmed = false;
+            self.save_state();
+            if let Some(path) = &self.config.state_fifo {
+                if let Err(e) = state::broadcast(path, self.numlock) {
+                    log::warn!("{}: {e}", path.display());
+                }
+            }
+            if self.numlock {
+                self.activate()?;
+            } else {
+                self.fade_out()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn panic_reset(&mut self) -> std::io::Result<()> {
+        log::warn!("resetting");
+        let keys = keys(&self.config);
+        let release = keys
+            .iter()
+            .map(|key| InputEvent::new(EventType::KEY, key.code(), 0))
+            .collect::<Vec<_>>();
+        self.emit(&release)?;
+        self.pressed = None;
+        self.pressed_cell = None;
+        self.holding = None;
+        self.tracking = false;
+        self.scrolling = None;
+        self.lifting = None;
+        self.percent_tap = None;
+        self.calculator_tap = None;
+        self.toggle_armed = None;
+        self.recalibrate_since = None;
+        self.learn = None;
+        self.fade = None;
+        self.slots = Slots::default();
+        self.gesture = false;
+        self.down = false;
+        self.numlock = false;
+        self.save_state();
+        self.deactivate()
+    }
+
+    fn show_level(&mut self) -> std::io::Result<()> {
+        self.fade = None;
+        let level = if self.numlock {
+            self.effective_brightness()
+        } else {
+            0x00
+        };
+        self.write_brightness(level)?;
+        Ok(())
+    }
+
+    fn numlock_corner(&mut self) -> std::io::Result<()> {
+        self.flush_percent()?;
+        let cooldown = Duration::from_millis(self.config.toggle_cooldown_ms);
+        if self
+            .last_toggle
+            .is_none_or(|last_toggle| last_toggle.elapsed() >= cooldown)
+        {
+            self.last_toggle = Some(Instant::now());
+            self.toggle_corner()?;
+        }
+        Ok(())
+    }
+
+    fn toggle_corner(&mut self) -> std::io::Result<()> {
+        if self.config.confirm_toggle && self.toggle_armed.take().is_none() {
+            self.toggle_armed = Some(Instant::now());
+            self.fade = None;
+            let level = if self.numlock {
+                0x00
+            } else {
+                self.effective_brightness()
+            };
+            self.write_brightness(level)?;
+            return Ok(());
+        }
+        let numlock = self.numlock;
+        self.set_numlock(!numlock)?;
+        if self.numlock == numlock && self.config.confirm_toggle {
+            self.show_level()?;
+        }
+        Ok(())
+    }
+
+    fn set_layer(&mut self, layer: usize) -> std::io::Result<()> {
+        if layer >= self.config.layers.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("no layer {layer}"),
+            ));
+        }
+        self.flush_percent()?;
+        self.release()?;
+        self.layer = layer;
+        self.save_state();
+        Ok(())
+    }
+
+    fn restore_layer(&mut self, profile: usize, layer: usize) {
+        self.profile = if profile < self.profiles.len() {
+            profile
+        } else {
+            0
+        };
+        self.config = self.profiles[self.profile].clone();
+        self.layer = if layer < self.config.layers.len() {
+            layer
+        } else {
+            0
+        };
+    }
+
+    fn next_profile(&mut self) -> std::io::Result<()> {
+        self.flush_percent()?;
+        self.release()?;
+        self.profile = (self.profile + 1) % self.profiles.len();
+        log::info!("profile {}", self.profile);
+        self.config = self.profiles[self.profile].clone();
+        self.layer = self.layer.min(self.config.layers.len() - 1);
+        self.set_brightness(self.config.brightness)
+    }
+
+    fn status(&self) -> String {
+        let geometry = self.geometry();
+        format!(
+            "numlock {} pressed {:?} x {} y {} column {:?} row {:?} np {}-{} {}-{}",
+            self.numlock,
+            self.pressed,
+            self.x,
+            self.y,
+            self.column_raw(),
+            self.row_raw(),
+            geometry.left_np(),
+            geometry.right_np(),
+            geometry.top_np(),
+            geometry.bottom_np(),
+        )
+    }
+
+    fn command(&mut self, command: Command) -> std::io::Result<String> {
+        match command {
+            Command::Status => return Ok(self.status()),
+            Command::Reset => self.panic_reset(),
+            Command::On => self.set_numlock(true),
+            Command::Off => self.set_numlock(false),
+            Command::Toggle => self.set_numlock(!self.numlock),
+            Command::Brightness(brightness) => self.set_brightness(brightness),
+            Command::CycleBrightness => self.set_brightness(
+                BRIGHTNESS_LEVELS
+                    .into_iter()
+                    .find(|level| *level > self.last_brightness)
+                    .unwrap_or(BRIGHTNESS_LEVELS[0]),
+            ),
+            Command::Layer(layer) => self.set_layer(layer),
+        }?;
+        Ok("ok".into())
+    }
+
+    fn emitted(&self, key: Key) -> Key {
+        top_row_digit(key)
+            .filter(|_| self.config.top_row_digits)
+            .unwrap_or(key)
+    }
+
+    fn emit_press(&mut self, key: Key) -> std::io::Result<()> {
+        let code = self.emitted(key).code();
+        if key == Key::KEY_5 || self.config.shift_all {
+            self.emit(&[
+                InputEvent::new(EventType::KEY, Key::KEY_LEFTSHIFT.code(), 1),
+                InputEvent::new(EventType::KEY, code, 1),
+            ])
+        } else {
+            self.emit(&[InputEvent::new(EventType::KEY, code, 1)])
+        }
+    }
+
+    fn on_press(&mut self, key: Key) {
+        let Some((program, args)) = self
+            .config
+            .on_press_command
+            .as_ref()
+            .and_then(|command| command.split_first())
+        else {
+            return;
+        };
+        if self
+            .last_on_press
+            .is_some_and(|last_on_press| last_on_press.elapsed() < ON_PRESS_THROTTLE)
+        {
+            return;
+        }
+        let mut command = std::process::Command::new(program);
+        command.args(args).arg(format!("{key:?}"));
+        if self.spawn(&mut command) {
+            self.last_on_press = Some(Instant::now());
+        }
+    }
+
+    fn spawn(&mut self, command: &mut std::process::Command) -> bool {
+        match self.spawn_child(command) {
+            Some(child) => {
+                self.children.push(child);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn spawn_child(&mut self, command: &mut std::process::Command) -> Option<std::process::Child> {
+        self.children
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+        let running = self.children.len() + usize::from(self.lock_check.is_some());
+        if running >= self.config.max_children {
+            log::warn!("{running} helpers running, not spawning");
+            return None;
+        }
+        match command.stdin(std::process::Stdio::null()).spawn() {
+            Ok(child) => Some(child),
+            Err(e) => {
+                log::error!("{:?}: {e}", command.get_program());
+                None
+            }
+        }
+    }
+
+    fn measure(&mut self) {
+        if !self.config.measure_latency {
+            return;
+        }
+        let Some(landed) = self.landed.take() else {
+            return;
+        };
+        match SystemTime::now().duration_since(landed) {
+            Ok(latency) => {
+                log::info!("latency {latency:?}");
+                self.latency.add(latency);
+            }
+            Err(e) => log::warn!("{e}"),
+        }
+    }
+
+    fn flush_percent(&mut self) -> std::io::Result<()> {
+        if self.percent_tap.take().is_some() {
+            self.tap(Key::KEY_5)?;
+            self.on_press(Key::KEY_5);
+        }
+        Ok(())
+    }
+
+    fn hit(&mut self, key: Key) -> std::io::Result<()> {
+        if key != Key::KEY_5 {
+            self.flush_percent()?;
+        } else if self.config.percent_double_tap && self.percent_tap.take().is_none() {
+            self.percent_tap = Some(Instant::now());
+            return Ok(());
+        }
+        let cell_action = match key {
+            Key::KEY_KPEQUAL => &self.config.equals_action,
+            Key::KEY_KPSLASH => &self.config.op_divide,
+            Key::KEY_KPASTERISK => &self.config.op_multiply,
+            Key::KEY_KPMINUS => &self.config.op_minus,
+            Key::KEY_KPPLUS => &self.config.op_plus,
+            _ => &None,
+        };
+        if let Some(action) = cell_action
+            .as_ref()
+            .or_else(|| self.config.actions.get(&KeyName(key)))
+            .or_else(|| {
+                character(key)
+                    .and_then(|character| self.config.characters.get(&character.to_string()))
+            })
+        {
+            self.perform(&action.clone())?;
+            self.measure();
+            self.on_press(key);
+            return Ok(());
+        }
+        self.emit_press(key)?;
+        self.measure();
+        self.pressed = Some(key);
+        self.on_press(key);
+        if self.config.tap_mode {
+            self.release()?;
+        }
+        Ok(())
+    }
+
+    fn click(&mut self) -> std::io::Result<()> {
+        if let Some(pointer) = &mut self.pointer {
+            pointer.emit(&[InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 1)])?;
+            pointer.emit(&[InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 0)])?;
+        }
+        Ok(())
+    }
+
+    fn miss(&mut self) -> std::io::Result<()> {
+        match self.config.none_action {
+            NoneAction::Ignore => {}
+            NoneAction::PassthroughTap => self.click()?,
+            NoneAction::Beep => self.beep()?,
+        }
+        Ok(())
+    }
+
+    fn beep(&mut self) -> std::io::Result<()> {
+        if self.bell.is_none() {
+            self.bell = find_device(|device| {
+                device
+                    .supported_sounds()
+                    .is_some_and(|sounds| sounds.contains(SoundType::SND_BELL))
+            })?
+            .map(|(path, device)| {
+                log::info!("bell {}", path.display());
+                device
+            });
+        }
+        let Some(bell) = &mut self.bell else {
+            log::warn!("no input device can ring a bell");
+            return Ok(());
+        };
+        bell.send_events(&[InputEvent::new(EventType::SOUND, SoundType::SND_BELL.0, 1)])?;
+        self.bell_until = Some(Instant::now() + BELL);
+        Ok(())
+    }
+
+    fn scroll_hit(&self) -> bool {
+        self.config
+            .scroll_strip
+            .is_some_and(|strip| self.right_percent() < strip)
+    }
+
+    fn scroll(&mut self) -> std::io::Result<()> {
+        let Some(anchor) = self.scrolling else {
+            return Ok(());
+        };
+        let step = (self.config.scroll_step * self.height()).max(1);
+        let clicks = (self.y - anchor) / step;
+        if clicks != 0 {
+            self.scrolling = Some(anchor + clicks * step);
+            if let Some(pointer) = &mut self.pointer {
+                pointer.emit(&[InputEvent::new(
+                    EventType::RELATIVE,
+                    RelativeAxisType::REL_WHEEL.0,
+                    -clicks,
+                )])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lift(&mut self) -> std::io::Result<()> {
+        self.last_release = Some(Instant::now());
+        self.scrolling = None;
+        if let Some((key, _)) = self.holding.take() {
+            self.hit(key)?;
+        }
+        if std::mem::take(&mut self.tracking) {
+            match self.key() {
+                Some(key) => self.hit(key)?,
+                None => self.miss()?,
+            }
+        }
+        self.release()
+    }
+
+    fn release(&mut self) -> std::io::Result<()> {
+        self.pressed_cell = None;
+        self.holding = None;
+        self.tracking = false;
+        if let Some(button) = self.pressed.take() {
+            self.emit(&[
+                InputEvent::new(EventType::KEY, Key::KEY_LEFTSHIFT.code(), 0),
+                InputEvent::new(EventType::KEY, self.emitted(button).code(), 0),
+            ])?
+        }
+        Ok(())
+    }
+
+    fn perform(&mut self, action: &Action) -> std::io::Result<()> {
+        match action {
+            Action::Combo(keys) => {
+                let press = keys
+                    .iter()
+                    .map(|key| InputEvent::new(EventType::KEY, key.code(), 1));
+                let release = keys
+                    .iter()
+                    .rev()
+                    .map(|key| InputEvent::new(EventType::KEY, key.code(), 0));
+                self.emit(&press.collect::<Vec<_>>())?;
+                self.emit(&release.collect::<Vec<_>>())
+            }
+            Action::Backspaces(count) => {
+                for _ in 0..*count {
+                    self.tap(Key::KEY_BACKSPACE)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn calculator(&mut self) {
+        if let Err(e) = self.perform(&self.config.calc_action.clone()) {
+            log::error!("{e}")
+        }
+    }
+
+    fn geometry(&self) -> Geometry {
+        Geometry::new((self.minx, self.maxx, self.miny, self.maxy), &self.config)
+    }
+
+    fn width(&self) -> i32 {
+        self.maxx - self.minx
+    }
+
+    fn height(&self) -> i32 {
+        self.maxy - self.miny
+    }
+
+    fn left_percent(&self) -> Percent {
+        Percent::div(self.x - self.minx, self.width())
+    }
+
+    fn right_percent(&self) -> Percent {
+        Percent::div(self.maxx - self.x, self.width())
+    }
+
+    fn top_percent(&self) -> Percent {
+        Percent::div(self.y - self.miny, self.height())
+    }
+
+    fn _bottom_percent(&self) -> Percent {
+        Percent::div(self.maxy - self.y, self.height())
+    }
+
+    fn numlock_hit(&self) -> bool {
+        let (Percent(width), Percent(height)) = numlock_corner(&self.config);
+        let Percent(margin) = self.config.numlock_edge_margin;
+        self.right_percent() < Percent(width + margin)
+            && self.top_percent() < Percent(height + margin)
+    }
+
+    fn calculator_hit(&self) -> bool {
+        let (width, height) = calculator_corner(&self.config);
+        self.left_percent() < width && self.top_percent() < height
+    }
+
+    fn corner_wins(&self) -> bool {
+        self.config.corner_precedence == CornerPrecedence::Corner
+            || !self.numlock
+            || self.key().is_none()
     }
 
     fn column_raw(&self) -> Option<usize> {
-        (non_neg_sub(self.x, self.left_np())? * i32::try_from(COLS).ok()?)
-            .checked_div(self.width_np() + 1)?
-            .try_into()
-            .ok()
+        self.geometry().in_row(self.row_raw()?).column(self.x)
     }
 
     fn row_raw(&self) -> Option<usize> {
-        (non_neg_sub(self.y, self.top_np())? * i32::try_from(ROWS).ok()?)
-            .checked_div(self.height_np() + 1)?
-            .try_into()
-            .ok()
+        self.geometry().row(self.y)
     }
 
-    fn column(&self, row: [Key; COLS]) -> Option<Key> {
-        row.get(self.column_raw()?).copied()
+    fn column(&self, row: &[KeyName; COLS]) -> Option<Key> {
+        Some(row.get(self.column_raw()?)?.0)
     }
 
-    fn row(&self) -> Option<[Key; COLS]> {
-        KEYS.get(self.row_raw()?).copied()
+    fn row(&self) -> Option<&[KeyName; COLS]> {
+        self.config.layers[self.layer].get(self.row_raw()?)
     }
 
     fn key(&self) -> Option<Key> {
-        self.column(self.row()?)
+        match self.column(self.row()?)? {
+            Key::KEY_KPDOT => Some(match self.config.decimal_separator {
+                DecimalSeparator::Dot => Key::KEY_KPDOT,
+                DecimalSeparator::Comma => Key::KEY_KPCOMMA,
+            }),
+            Key::KEY_BACKSPACE
+                if self.config.backspace_as_delete && self.column_raw() == Some(COLS - 1) =>
+            {
+                Some(Key::KEY_DELETE)
+            }
+            key => Some(key),
+        }
+    }
+
+    fn recalibrate_deadline(&self) -> Option<Instant> {
+        Some(self.recalibrate_since? + Duration::from_millis(self.config.recalibrate_hold_ms?))
+    }
+
+    fn regrab_deadline(&self) -> Option<Instant> {
+        Some(self.activated? + Duration::from_millis(self.config.regrab_after_activate_ms?))
+    }
+
+    fn lift_deadline(&self) -> Option<Instant> {
+        Some(self.lifting? + Duration::from_millis(self.config.lift_debounce_ms))
+    }
+
+    fn hold_deadline(&self) -> Option<Instant> {
+        let (_, since) = self.holding?;
+        Some(since + Duration::from_millis(self.config.hold_time_ms))
+    }
+
+    fn power_deadline(&self) -> Option<Instant> {
+        (self.config.on_battery != OnBattery::Normal).then_some(self.next_power_poll)
+    }
+
+    fn disable_deadline(&self) -> Option<Instant> {
+        self.config
+            .disable_file
+            .is_some()
+            .then_some(self.next_disable_poll)
+    }
+
+    fn lock_deadline(&self) -> Option<Instant> {
+        self.config
+            .locked_command
+            .is_some()
+            .then_some(self.next_lock_poll)
+    }
+
+    fn toggle_deadline(&self) -> Option<Instant> {
+        Some(self.toggle_armed? + Duration::from_millis(self.config.confirm_toggle_ms))
+    }
+
+    fn session_deadline(&self) -> Option<Instant> {
+        (self.config.release_on_session_change || self.config.keep_across_vt)
+            .then_some(self.next_session_poll)
+    }
+
+    fn dim_deadline(&self) -> Option<Instant> {
+        let dim_after = Duration::from_millis(self.config.dim_after_ms?);
+        (self.numlock && !self.dimmed).then_some(self.last_touch + dim_after)
+    }
+
+    fn calculator_deadline(&self) -> Option<Instant> {
+        Some(self.calculator_tap? + Duration::from_millis(self.config.double_tap_ms))
+    }
+
+    fn percent_deadline(&self) -> Option<Instant> {
+        Some(self.percent_tap? + Duration::from_millis(self.config.double_tap_ms))
+    }
+
+    fn fade_deadline(&self) -> Option<Instant> {
+        Some(self.fade.as_ref()?.next)
+    }
+
+    fn i2c_retry_deadline(&self) -> Option<Instant> {
+        Some(self.i2c_retry.as_ref()?.next)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        [
+            self.fade_deadline(),
+            self.i2c_retry_deadline(),
+            self.hold_deadline(),
+            self.lift_deadline(),
+            self.regrab_deadline(),
+            self.recalibrate_deadline(),
+            self.power_deadline(),
+            self.disable_deadline(),
+            self.lock_deadline(),
+            self.session_deadline(),
+            self.dim_deadline(),
+            self.calculator_deadline(),
+            self.percent_deadline(),
+            self.bell_until,
+            self.toggle_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn tick(&mut self) -> std::io::Result<()> {
+        if due(self.disable_deadline()) {
+            self.poll_disable()?;
+        }
+        if due(self.fade_deadline()) {
+            self.fade_step()?;
+        }
+        if due(self.i2c_retry_deadline()) {
+            if let Some(retry) = self.i2c_retry.take() {
+                self.retry_write(retry)?;
+            }
+        }
+        if due(self.dim_deadline()) {
+            self.dimmed = true;
+            self.apply_brightness()?;
+        }
+        if due(self.toggle_deadline()) {
+            self.toggle_armed = None;
+            self.show_level()?;
+        }
+        if due(self.calculator_deadline()) {
+            self.calculator_tap = None;
+            self.calculator();
+        }
+        if due(self.bell_until) {
+            self.bell_until = None;
+            if let Some(bell) = &mut self.bell {
+                bell.send_events(&[InputEvent::new(EventType::SOUND, SoundType::SND_BELL.0, 0)])?;
+            }
+        }
+        if due(self.percent_deadline()) {
+            self.flush_percent()?;
+        }
+        if due(self.power_deadline()) {
+            if let Err(e) = self.poll_power() {
+                log::error!("{e}")
+            }
+        }
+        if due(self.lock_deadline()) {
+            if let Err(e) = self.poll_lock() {
+                log::error!("{e}")
+            }
+        }
+        if due(self.session_deadline()) {
+            if let Err(e) = self.poll_session() {
+                log::error!("{e}")
+            }
+        }
+        if due(self.recalibrate_deadline()) {
+            self.recalibrate_since = None;
+            self.recalibrate()?;
+        }
+        if due(self.regrab_deadline()) {
+            self.activated = None;
+            self.regrab = true;
+        }
+        if due(self.lift_deadline()) {
+            self.lifting = None;
+            self.lift()?;
+        }
+        if due(self.hold_deadline()) {
+            if let Some((key, _)) = self.holding.take() {
+                let key = self
+                    .config
+                    .hold_keys
+                    .get(&KeyName(key))
+                    .map_or(key, |key| key.0);
+                self.hit(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn vt_matches(&self) -> bool {
+        let Some(vt) = self.config.vt else {
+            return true;
+        };
+        match vt::active() {
+            Ok(active) => active == vt,
+            Err(e) => {
+                log::error!("{e}");
+                true
+            }
+        }
+    }
+
+    fn settling(&self) -> bool {
+        self.last_release.is_some_and(|last_release| {
+            last_release.elapsed() < Duration::from_millis(self.config.settle_ms)
+        })
+    }
+
+    fn sticky(&self) -> bool {
+        self.config.sticky_numlock
+            && self.last_toggle.is_some_and(|last_toggle| {
+                last_toggle.elapsed() < Duration::from_millis(self.config.sticky_numlock_ms)
+            })
+            && self.numlock_hit()
+    }
+
+    fn idle(&self) -> bool {
+        self.pressed.is_none()
+            && self.holding.is_none()
+            && !self.tracking
+            && self.scrolling.is_none()
     }
 
     fn press(&mut self) -> std::io::Result<()> {
-        if self.pressed.is_none() {
-            if self.numlock_hit() {
-                self.numlock = !self.numlock;
-                if self.numlock {
-                    self.activate()?;
+        if self.idle()
+            && !self.disabled
+            && !self.switched_off
+            && !self.locked
+            && !self.settling()
+            && self.vt_matches()
+        {
+            if self.corner_wins() && self.numlock_hit() {
+                self.numlock_corner()?;
+            } else if self.corner_wins() && self.calculator_hit() {
+                self.flush_percent()?;
+                if self.profiles.len() < 2 {
+                    self.calculator();
+                } else if self.calculator_tap.take().is_some() {
+                    self.next_profile()?;
                 } else {
-                    self.deactivate()?;
+                    self.calculator_tap = Some(Instant::now());
                 }
-            } else if self.calculator_hit() {
-                self.calculator();
+            } else if !self.numlock && self.config.button_mode {
+                self.click()?;
+            } else if self.numlock && self.scroll_hit() {
+                self.scrolling = Some(self.y);
             } else if self.numlock {
-                if let Some(key) = self.key() {
-                    if key == Key::KEY_5 {
-                        self.udev.emit(&[
-                            InputEvent::new(EventType::KEY, Key::KEY_LEFTSHIFT.code(), 1),
-                            InputEvent::new(EventType::KEY, Key::KEY_5.code(), 1),
-                        ])?
-                    } else {
-                        self.udev
-                            .emit(&[InputEvent::new(EventType::KEY, key.code(), 1)])?
+                if let Some((row, column)) = self.cell() {
+                    self.heatmap[row][column] += 1;
+                }
+                if self.config.emit_on_release {
+                    self.tracking = true;
+                } else {
+                    match self.key() {
+                        Some(key) if self.config.hold_keys.contains_key(&KeyName(key)) => {
+                            self.holding = Some((key, Instant::now()));
+                        }
+                        Some(key) => {
+                            self.pressed_cell = self.cell();
+                            self.pressed_at = (self.x, self.y);
+                            self.hit(key)?;
+                        }
+                        None => self.miss()?,
                     }
-                    self.pressed = Some(key);
                 }
             }
         }
         Ok(())
     }
 
+    fn cell(&self) -> Option<(usize, usize)> {
+        Some((self.row_raw()?, self.column_raw()?))
+    }
+
+    fn slide(&mut self) -> std::io::Result<()> {
+        let Some(pressed) = self.pressed else {
+            return Ok(());
+        };
+        let cell = self.cell();
+        if self.pressed_cell.is_none() || self.pressed_cell == cell {
+            return Ok(());
+        }
+        let (x, y) = self.pressed_at;
+        let travel = Percent::div((self.x - x).abs(), self.width())
+            .max(Percent::div((self.y - y).abs(), self.height()));
+        if travel < self.config.slide_travel {
+            return Ok(());
+        }
+        let key = self.key();
+        if self.config.merge_identical && key == Some(pressed) {
+            self.pressed_cell = cell;
+            return Ok(());
+        }
+        self.release()?;
+        if let Some(key) = key {
+            self.pressed_cell = cell;
+            self.pressed_at = (self.x, self.y);
+            self.hit(key)?;
+        }
+        Ok(())
+    }
+
+    fn position(&mut self, axis: AbsoluteAxisType, value: i32) {
+        let is_x = (axis == self.axes[0]) != self.config.transpose;
+        let value = match (self.rotated(), is_x) {
+            (false, _) => value,
+            (true, true) => self.minx + self.maxx - value,
+            (true, false) => self.miny + self.maxy - value,
+        };
+        if self.config.corner_gate || self.config.recalibrate_hold_ms.is_some() {
+            let slots = &mut self.slots;
+            let contact = slots.contacts.entry(slots.slot).or_default();
+            if is_x {
+                contact.0 = value;
+            } else {
+                contact.1 = value;
+            }
+            if self.config.corner_gate && slots.typing != Some(slots.slot) {
+                return;
+            }
+        }
+        if is_x {
+            self.x = value;
+        } else {
+            self.y = value;
+        }
+    }
+
+    fn rotated(&self) -> bool {
+        self.config.rotate != (self.config.auto_rotate && self.tablet)
+    }
+
+    fn with_switch(&mut self, switch: &mut Device) -> std::io::Result<()> {
+        for e in switch.fetch_events()? {
+            if e.kind() == InputEventKind::Switch(SwitchType::SW_TABLET_MODE) {
+                self.tablet = e.value() != 0;
+                log::info!("tablet mode {}", self.tablet);
+                self.release()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn learned(&self, learn: &Learn) -> [(&'static str, Percent); 4] {
+        [
+            (
+                "left_offset",
+                Percent::div(learn.left() - self.minx, self.width()),
+            ),
+            (
+                "right_offset",
+                Percent::div(self.maxx - learn.right(), self.width()),
+            ),
+            (
+                "top_offset",
+                Percent::div(learn.top() - self.miny, self.height()),
+            ),
+            (
+                "bottom_offset",
+                Percent::div(self.maxy - learn.bottom(), self.height()),
+            ),
+        ]
+    }
+
+    fn corners_held(&self) -> bool {
+        let in_corner =
+            |&(x, y): &(i32, i32), from_left: bool, (width, height): (Percent, Percent)| {
+                let from_edge = if from_left {
+                    x - self.minx
+                } else {
+                    self.maxx - x
+                };
+                Percent::div(from_edge, self.width()) < width
+                    && Percent::div(y - self.miny, self.height()) < height
+            };
+        let contacts = &self.slots.contacts;
+        contacts
+            .values()
+            .any(|contact| in_corner(contact, false, numlock_corner(&self.config)))
+            && contacts
+                .values()
+                .any(|contact| in_corner(contact, true, calculator_corner(&self.config)))
+    }
+
+    fn recalibrate(&mut self) -> std::io::Result<()> {
+        log::info!("recalibrating");
+        self.release()?;
+        self.learn = Some(Learn::new());
+        self.write_brightness(self.last_brightness)?;
+        Ok(())
+    }
+
+    fn recalibrated(&mut self) -> std::io::Result<()> {
+        let Some(learn) = self.learn.take() else {
+            return Ok(());
+        };
+        let [left, right, top, bottom] = self.learned(&learn);
+        for (name, offset) in [left, right, top, bottom] {
+            log::info!("{name} = {}", offset.0);
+        }
+        self.config.left_offset = left.1;
+        self.config.right_offset = right.1;
+        self.config.top_offset = top.1;
+        self.config.bottom_offset = bottom.1;
+        if self.numlock {
+            self.apply_brightness()
+        } else {
+            self.deactivate()
+        }
+    }
+
+    fn sync_gated(&mut self) -> std::io::Result<()> {
+        for slot in std::mem::take(&mut self.slots.lifted) {
+            self.slots.contacts.remove(&slot);
+            if self.slots.gate == Some(slot) {
+                self.slots.gate = None;
+                self.slots.typing = None;
+                self.set_numlock(false)?;
+            } else if self.slots.typing == Some(slot) {
+                self.slots.typing = None;
+                self.lift()?;
+            }
+        }
+        for slot in std::mem::take(&mut self.slots.landed) {
+            let Some(&(x, y)) = self.slots.contacts.get(&slot) else {
+                continue;
+            };
+            (self.x, self.y) = (x, y);
+            if self.slots.gate.is_none() {
+                if self.numlock_hit() {
+                    self.slots.gate = Some(slot);
+                    self.set_numlock(true)?;
+                }
+            } else if self.slots.typing.is_none() {
+                self.slots.typing = Some(slot);
+                self.wake()?;
+                self.press()?;
+            }
+        }
+        if self.config.slide && self.slots.typing.is_some() {
+            self.slide()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if self.tools > 1 && !self.gesture {
+            log::debug!("more than one finger, ignoring touches");
+            self.gesture = true;
+        }
+        if self.gesture {
+            self.finger = None;
+            self.lifting = None;
+            if self.config.recalibrate_hold_ms.is_some() && self.learn.is_none() {
+                if !self.corners_held() {
+                    self.recalibrate_since = None;
+                } else if self.recalibrate_since.is_none() {
+                    self.recalibrate_since = Some(Instant::now());
+                }
+            }
+            if self.tools == 0 {
+                self.gesture = false;
+            }
+            return self.release();
+        }
+        let new_contact = std::mem::take(&mut self.new_contact);
+        if let Some(finger) = self.finger {
+            self.down = finger;
+        }
+        let sticky = self.sticky();
+        match (self.finger.take(), &mut self.learn) {
+            (Some(false), Some(learn)) => learn.tap(self.x, self.y),
+            (Some(false), None) if sticky => {
+                log::debug!("ignoring lift in the numlock corner")
+            }
+            (Some(true), None) if sticky => {
+                log::debug!("ignoring touch in the numlock corner")
+            }
+            (Some(false), None) if self.config.lift_debounce_ms > 0 => {
+                self.lifting = Some(Instant::now())
+            }
+            (Some(false), None) => self.lift()?,
+            (Some(true), None) if self.lifting.take().is_some() => {
+                log::debug!("finger bounced, keeping the touch");
+                self.wake()?
+            }
+            (Some(true), None) => {
+                self.wake()?;
+                self.press()?
+            }
+            (None, None) if new_contact && self.down => {
+                log::debug!("finger swapped without lifting");
+                self.lift()?;
+                self.wake()?;
+                self.press()?
+            }
+            (None, None) if self.scrolling.is_some() => self.scroll()?,
+            (None, None) if self.config.slide => self.slide()?,
+            _ => {}
+        }
+        if !self.config.learn && self.learn.as_ref().is_some_and(Learn::done) {
+            self.recalibrated()?;
+        }
+        Ok(())
+    }
+
     fn with_touchpad(&mut self, touchpad: &mut Device) -> std::io::Result<()> {
-        for e in touchpad.fetch_events()? {
-            match e.kind() {
-                InputEventKind::Key(Key::BTN_TOOL_FINGER) => match e.value() {
-                    0 => self.release()?,
-                    1 => self.press()?,
+        if self.config.batch_events {
+            self.batch = Some(Vec::new());
+        }
+        let result = touchpad
+            .fetch_events()
+            .and_then(|mut events| events.try_for_each(|e| self.event(e)));
+        if let Some(batch) = self.batch.take().filter(|batch| !batch.is_empty()) {
+            self.udev.emit(&batch)?;
+        }
+        result
+    }
+
+    fn event(&mut self, e: InputEvent) -> std::io::Result<()> {
+        if let InputEventKind::Key(key) = e.kind() {
+            if let Some(fingers) = TOOLS.iter().position(|tool| *tool == key) {
+                if e.value() == 0 {
+                    self.tools &= !(1 << fingers);
+                } else {
+                    self.tools |= 1 << fingers;
+                }
+            }
+        }
+        match e.kind() {
+            InputEventKind::Key(key) if key == self.finger_key && !self.config.corner_gate => {
+                match e.value() {
+                    0 => self.finger = Some(false),
+                    1 => {
+                        self.finger = Some(true);
+                        self.landed = Some(e.timestamp());
+                    }
                     _ => {}
-                },
-                InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_POSITION_X) => self.x = e.value(),
-                InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_POSITION_Y) => self.y = e.value(),
-                _ => {}
+                }
+            }
+            InputEventKind::Synchronization(Synchronization::SYN_REPORT)
+                if self.config.corner_gate =>
+            {
+                self.sync_gated()?
+            }
+            InputEventKind::Synchronization(Synchronization::SYN_REPORT) => self.sync()?,
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_SLOT) => self.slots.slot = e.value(),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_TRACKING_ID)
+                if self.config.corner_gate =>
+            {
+                if e.value() < 0 {
+                    self.slots.lifted.push(self.slots.slot);
+                } else {
+                    self.slots.landed.push(self.slots.slot);
+                }
+            }
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_TRACKING_ID) if e.value() < 0 => {
+                self.slots.contacts.remove(&self.slots.slot);
             }
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_TRACKING_ID)
+                if self.config.track_contacts =>
+            {
+                self.new_contact = true
+            }
+            InputEventKind::AbsAxis(axis) if self.axes.contains(&axis) => {
+                self.position(axis, e.value())
+            }
+            _ => {}
         }
         Ok(())
     }
 }
 
+enum Step {
+    Touchpad,
+    Switch,
+    Master,
+    Request(Request),
+    Deadline,
+    Watchdog,
+}
+
 struct Context {
     no_touch: NoTouch,
     touchpad: Async<Device>,
+    switch: Option<Async<Device>>,
+    master: Option<Async<Device>>,
 }
 
 impl Drop for Context {
@@ -439,9 +2474,27 @@ impl Drop for Context {
 }
 
 impl Context {
-    fn grab(&mut self) -> std::io::Result<()> {
-        self.touchpad.as_mut().grab()?;
-        Ok(())
+    async fn grab(&mut self) -> std::io::Result<()> {
+        let mut tries = GRAB_TRY_TIMES;
+        loop {
+            match self.touchpad.as_mut().grab() {
+                Err(e)
+                    if e.raw_os_error() == Some(Errno::EBUSY as i32)
+                        || e.raw_os_error() == Some(Errno::EINVAL as i32) =>
+                {
+                    log::warn!("touchpad is grabbed by another process");
+                    tries -= 1;
+                    if tries == 0 {
+                        return Err(std::io::Error::new(
+                            e.kind(),
+                            "touchpad is grabbed by another process",
+                        ));
+                    }
+                    Timer::after(GRAB_TRY_SLEEP).await;
+                }
+                result => return result,
+            }
+        }
     }
 
     fn ungrab(&mut self) -> std::io::Result<()> {
@@ -449,21 +2502,506 @@ impl Context {
         Ok(())
     }
 
-    async fn step(&mut self) -> std::io::Result<()> {
-        self.touchpad
-            .read_with_mut(|touchpad| self.no_touch.with_touchpad(touchpad))
-            .await?;
-        if self.no_touch.numlock {
-            self.grab()?
-        } else {
-            self.ungrab()?
+    async fn step(&mut self, requests: &Receiver<Request>) -> std::io::Result<()> {
+        let deadline = self.no_touch.deadline();
+        let timeout = Duration::from_secs(self.no_touch.config.read_timeout_secs);
+        let step = async {
+            self.touchpad.readable().await?;
+            Ok::<_, std::io::Error>(Step::Touchpad)
+        }
+        .race(async {
+            match &self.switch {
+                Some(switch) => {
+                    switch.readable().await?;
+                    Ok(Step::Switch)
+                }
+                None => futures_lite::future::pending().await,
+            }
+        })
+        .race(async {
+            match &self.master {
+                Some(master) => {
+                    master.readable().await?;
+                    Ok(Step::Master)
+                }
+                None => futures_lite::future::pending().await,
+            }
+        })
+        .race(async {
+            match requests.recv().await {
+                Ok(request) => Ok(Step::Request(request)),
+                Err(_) => futures_lite::future::pending().await,
+            }
+        })
+        .race(async {
+            match deadline {
+                Some(deadline) => {
+                    Timer::at(deadline).await;
+                    Ok(Step::Deadline)
+                }
+                None => futures_lite::future::pending().await,
+            }
+        })
+        .race(async {
+            Timer::after(timeout).await;
+            Ok(Step::Watchdog)
+        })
+        .await?;
+        match step {
+            Step::Touchpad => {
+                self.touchpad
+                    .read_with_mut(|touchpad| self.no_touch.with_touchpad(touchpad))
+                    .or(async {
+                        Timer::after(timeout).await;
+                        log::warn!("touchpad read stuck for {timeout:?}");
+                        Err(std::io::ErrorKind::TimedOut.into())
+                    })
+                    .await?
+            }
+            Step::Switch => {
+                if let Some(switch) = &mut self.switch {
+                    switch
+                        .read_with_mut(|switch| self.no_touch.with_switch(switch))
+                        .await?
+                }
+            }
+            Step::Master => {
+                if let Some(master) = &mut self.master {
+                    master
+                        .read_with_mut(|master| self.no_touch.with_master(master))
+                        .await?
+                }
+            }
+            Step::Request(Request { command, reply }) => {
+                let response = self
+                    .no_touch
+                    .command(command)
+                    .unwrap_or_else(|e| format!("error: {e}"));
+                let _ = reply.try_send(response);
+            }
+            Step::Deadline => self.no_touch.tick()?,
+            Step::Watchdog => {
+                if let Err(e) = self.touchpad.get_ref().get_abs_state() {
+                    log::warn!("touchpad stopped answering after {timeout:?} idle: {e}");
+                    return Err(e);
+                }
+            }
+        }
+        if !self.no_touch.config.no_grab {
+            if self.no_touch.numlock
+                || self.no_touch.config.button_mode
+                || self.no_touch.learn.is_some()
+            {
+                if std::mem::take(&mut self.no_touch.regrab) {
+                    log::debug!("grabbing again after activation");
+                    match self.ungrab() {
+                        Err(e) if e.raw_os_error() == Some(Errno::EINVAL as i32) => {
+                            log::debug!("the grab was already lost");
+                            unsafe { eviocgrab(self.touchpad.get_ref().as_raw_fd(), 1) }?;
+                        }
+                        result => result?,
+                    }
+                }
+                self.grab().await?
+            } else {
+                self.ungrab()?
+            }
+        }
+        Ok(())
+    }
+
+    async fn learn(&mut self) -> std::io::Result<()> {
+        self.no_touch.learn = Some(Learn::new());
+        self.no_touch
+            .write_brightness(self.no_touch.last_brightness)?;
+        if !self.no_touch.config.no_grab {
+            self.grab().await?;
+        }
+        while !self.no_touch.learn.as_ref().is_some_and(Learn::done) {
+            self.touchpad
+                .read_with_mut(|touchpad| self.no_touch.with_touchpad(touchpad))
+                .await?;
+        }
+        if let Some(learn) = self.no_touch.learn.take() {
+            for (name, offset) in self.no_touch.learned(&learn) {
+                println!("{name} = {}", offset.0);
+            }
         }
         Ok(())
     }
 
-    async fn run(&mut self) -> std::io::Result<()> {
+    async fn run(&mut self, requests: &Receiver<Request>) -> std::io::Result<()> {
         loop {
-            self.step().await?
+            self.step(requests).await?
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::config::Profile;
+
+    type Events = Rc<RefCell<Vec<(Key, i32)>>>;
+
+    struct Recorder(Events);
+
+    impl KeySink for Recorder {
+        fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+            for event in events {
+                if let InputEventKind::Key(key) = event.kind() {
+                    self.0.borrow_mut().push((key, event.value()));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    type Writes = Rc<RefCell<Vec<Vec<u8>>>>;
+
+    struct Bus(Writes);
+
+    impl I2cWrite for Bus {
+        fn write_message(&mut self, data: &[u8]) -> std::io::Result<u32> {
+            self.0.borrow_mut().push(data.to_vec());
+            Ok(1)
+        }
+    }
+
+    fn no_touch(events: &Events) -> NoTouch {
+        no_touch_with(events, &Config::default())
+    }
+
+    fn no_touch_with(events: &Events, config: &Config) -> NoTouch {
+        no_touch_on(&Writes::default(), events, config)
+    }
+
+    fn no_touch_on(writes: &Writes, events: &Events, config: &Config) -> NoTouch {
+        NoTouch::new(
+            Box::new(Bus(writes.clone())),
+            "0".into(),
+            Box::new(Recorder(events.clone())),
+            None,
+            (0, 1000, 0, 1000),
+            [
+                AbsoluteAxisType::ABS_MT_POSITION_X,
+                AbsoluteAxisType::ABS_MT_POSITION_Y,
+            ],
+            Key::BTN_TOOL_FINGER,
+            DEFAULT_BRIGHTNESS,
+            config,
+        )
+    }
+
+    fn touch(no_touch: &mut NoTouch, x: i32, y: i32) {
+        (no_touch.x, no_touch.y) = (x, y);
+        no_touch.press().unwrap();
+    }
+
+    #[test]
+    fn toggle_off_releases_held_key() {
+        let events = Events::default();
+        let mut no_touch = no_touch(&events);
+        no_touch.set_numlock(true).unwrap();
+        touch(&mut no_touch, 200, 300);
+        assert_eq!(no_touch.pressed, Some(Key::KEY_KP7));
+        no_touch.command(Command::Toggle).unwrap();
+        assert!(!no_touch.numlock);
+        assert_eq!(no_touch.pressed, None);
+        assert!(events.borrow().contains(&(Key::KEY_KP7, 0)));
+    }
+
+    #[test]
+    fn corner_toggle_leaves_nothing_pressed() {
+        let events = Events::default();
+        let mut no_touch = no_touch(&events);
+        no_touch.set_numlock(true).unwrap();
+        events.borrow_mut().clear();
+        touch(&mut no_touch, 200, 300);
+        no_touch.lift().unwrap();
+        touch(&mut no_touch, 990, 10);
+        assert!(!no_touch.numlock);
+        assert_eq!(no_touch.pressed, None);
+        assert_eq!(
+            *events.borrow(),
+            [
+                (Key::KEY_KP7, 1),
+                (Key::KEY_LEFTSHIFT, 0),
+                (Key::KEY_KP7, 0),
+                (Key::KEY_NUMLOCK, 0),
+            ]
+        );
+    }
+
+    fn feed(no_touch: &mut NoTouch, frame: &[(AbsoluteAxisType, i32)], finger: Option<i32>) {
+        for &(axis, value) in frame {
+            no_touch
+                .event(InputEvent::new(EventType::ABSOLUTE, axis.0, value))
+                .unwrap();
+        }
+        if let Some(value) = finger {
+            no_touch
+                .event(InputEvent::new(
+                    EventType::KEY,
+                    Key::BTN_TOOL_FINGER.code(),
+                    value,
+                ))
+                .unwrap();
+        }
+        no_touch
+            .event(InputEvent::new(
+                EventType::SYNCHRONIZATION,
+                Synchronization::SYN_REPORT.0,
+                0,
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn finger_swap_within_a_frame_presses_again() {
+        let events = Events::default();
+        let mut no_touch = no_touch(&events);
+        no_touch.set_numlock(true).unwrap();
+        events.borrow_mut().clear();
+        feed(
+            &mut no_touch,
+            &[
+                (AbsoluteAxisType::ABS_MT_SLOT, 0),
+                (AbsoluteAxisType::ABS_MT_TRACKING_ID, 1),
+                (AbsoluteAxisType::ABS_MT_POSITION_X, 200),
+                (AbsoluteAxisType::ABS_MT_POSITION_Y, 300),
+            ],
+            Some(1),
+        );
+        assert_eq!(no_touch.pressed, Some(Key::KEY_KP7));
+        feed(
+            &mut no_touch,
+            &[
+                (AbsoluteAxisType::ABS_MT_TRACKING_ID, -1),
+                (AbsoluteAxisType::ABS_MT_SLOT, 1),
+                (AbsoluteAxisType::ABS_MT_TRACKING_ID, 2),
+                (AbsoluteAxisType::ABS_MT_POSITION_X, 500),
+                (AbsoluteAxisType::ABS_MT_POSITION_Y, 300),
+            ],
+            None,
+        );
+        assert_eq!(no_touch.pressed, Some(Key::KEY_KP9));
+        feed(
+            &mut no_touch,
+            &[(AbsoluteAxisType::ABS_MT_TRACKING_ID, -1)],
+            Some(0),
+        );
+        assert_eq!(no_touch.pressed, None);
+        assert_eq!(
+            *events.borrow(),
+            [
+                (Key::KEY_KP7, 1),
+                (Key::KEY_LEFTSHIFT, 0),
+                (Key::KEY_KP7, 0),
+                (Key::KEY_KP9, 1),
+                (Key::KEY_LEFTSHIFT, 0),
+                (Key::KEY_KP9, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn profile_only_keys_are_registered() {
+        let mut layout = KEYS.map(|row| row.map(KeyName));
+        layout[0][0] = KeyName(Key::KEY_F13);
+        let mut config = Config::default();
+        config.profiles.push(Profile {
+            layers: Some(vec![layout]),
+            left_offset: None,
+            right_offset: None,
+            top_offset: None,
+            bottom_offset: None,
+            brightness: None,
+        });
+        assert!(!keys(&Config::default()).contains(Key::KEY_F13));
+        assert!(keys(&config).contains(Key::KEY_F13));
+    }
+
+    #[test]
+    fn custom_sequences_skip_brightness_frames() {
+        let writes = Writes::default();
+        let config = Config {
+            activate_hex: Some("01 02 03".into()),
+            deactivate_hex: Some("04 05".into()),
+            ..Config::default()
+        };
+        let mut no_touch = no_touch_on(&writes, &Events::default(), &config);
+        no_touch.set_numlock(true).unwrap();
+        no_touch.set_brightness(MIN_BRIGHTNESS).unwrap();
+        no_touch.set_numlock(false).unwrap();
+        assert_eq!(*writes.borrow(), [vec![1, 2, 3], vec![4, 5]]);
+    }
+
+    #[test]
+    fn pending_percent_tap_goes_out_before_the_next_key() {
+        let events = Events::default();
+        let config = Config {
+            percent_double_tap: true,
+            ..Config::default()
+        };
+        let mut no_touch = no_touch_with(&events, &config);
+        no_touch.set_numlock(true).unwrap();
+        events.borrow_mut().clear();
+        no_touch.hit(Key::KEY_5).unwrap();
+        assert!(events.borrow().is_empty());
+        no_touch.hit(Key::KEY_KP3).unwrap();
+        assert_eq!(no_touch.percent_tap, None);
+        assert_eq!(
+            *events.borrow(),
+            [(Key::KEY_5, 1), (Key::KEY_5, 0), (Key::KEY_KP3, 1)]
+        );
+    }
+
+    #[test]
+    fn second_finger_in_the_corner_releases_held_key() {
+        let events = Events::default();
+        let mut no_touch = no_touch(&events);
+        no_touch.set_numlock(true).unwrap();
+        events.borrow_mut().clear();
+        feed(
+            &mut no_touch,
+            &[
+                (AbsoluteAxisType::ABS_MT_SLOT, 0),
+                (AbsoluteAxisType::ABS_MT_TRACKING_ID, 1),
+                (AbsoluteAxisType::ABS_MT_POSITION_X, 200),
+                (AbsoluteAxisType::ABS_MT_POSITION_Y, 300),
+            ],
+            Some(1),
+        );
+        assert_eq!(no_touch.pressed, Some(Key::KEY_KP7));
+        no_touch
+            .event(InputEvent::new(
+                EventType::KEY,
+                Key::BTN_TOOL_DOUBLETAP.code(),
+                1,
+            ))
+            .unwrap();
+        feed(
+            &mut no_touch,
+            &[
+                (AbsoluteAxisType::ABS_MT_SLOT, 1),
+                (AbsoluteAxisType::ABS_MT_TRACKING_ID, 2),
+                (AbsoluteAxisType::ABS_MT_POSITION_X, 990),
+                (AbsoluteAxisType::ABS_MT_POSITION_Y, 10),
+            ],
+            Some(0),
+        );
+        assert_eq!(no_touch.pressed, None);
+        assert_eq!(
+            *events.borrow(),
+            [
+                (Key::KEY_KP7, 1),
+                (Key::KEY_LEFTSHIFT, 0),
+                (Key::KEY_KP7, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn edge_margin_widens_the_numlock_corner() {
+        let events = Events::default();
+        let mut no_touch = no_touch(&events);
+        touch(&mut no_touch, 930, 120);
+        no_touch.lift().unwrap();
+        assert!(!no_touch.numlock);
+        let config = Config {
+            numlock_edge_margin: Percent(5),
+            ..Config::default()
+        };
+        let mut no_touch = no_touch_with(&events, &config);
+        touch(&mut no_touch, 930, 120);
+        no_touch.lift().unwrap();
+        assert!(no_touch.numlock);
+    }
+
+    #[test]
+    fn edge_margin_band_presses_on_landing_without_a_margin() {
+        let events = Events::default();
+        let mut no_touch = no_touch(&events);
+        no_touch.set_numlock(true).unwrap();
+        touch(&mut no_touch, 930, 120);
+        assert!(no_touch.numlock);
+        assert!(no_touch.pressed.is_some());
+    }
+
+    #[test]
+    fn batch_splits_frames_between_events_for_one_key() {
+        let events = Events::default();
+        let config = Config {
+            tap_mode: true,
+            ..Config::default()
+        };
+        let mut no_touch = no_touch_with(&events, &config);
+        no_touch.set_numlock(true).unwrap();
+        no_touch.batch = Some(Vec::new());
+        touch(&mut no_touch, 200, 300);
+        touch(&mut no_touch, 500, 300);
+        let batch = no_touch
+            .batch
+            .take()
+            .unwrap()
+            .iter()
+            .map(|event| match event.kind() {
+                InputEventKind::Key(key) => Some((key, event.value())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batch,
+            [
+                Some((Key::KEY_KP7, 1)),
+                Some((Key::KEY_LEFTSHIFT, 0)),
+                None,
+                Some((Key::KEY_KP7, 0)),
+                Some((Key::KEY_KP9, 1)),
+                Some((Key::KEY_LEFTSHIFT, 0)),
+                None,
+                Some((Key::KEY_KP9, 0)),
+            ]
+        );
+    }
+
+    fn fullpad(corner_precedence: CornerPrecedence) -> Config {
+        let mut config = Config::default();
+        config.fullpad();
+        config.corner_precedence = corner_precedence;
+        config
+    }
+
+    #[test]
+    fn corner_wins_at_the_boundary() {
+        let events = Events::default();
+        let mut no_touch = no_touch_with(&events, &fullpad(CornerPrecedence::Corner));
+        no_touch.set_numlock(true).unwrap();
+        touch(&mut no_touch, 971, 49);
+        assert!(!no_touch.numlock);
+        assert!(!events.borrow().contains(&(Key::KEY_BACKSPACE, 1)));
+    }
+
+    #[test]
+    fn cell_wins_at_the_boundary() {
+        let events = Events::default();
+        let mut no_touch = no_touch_with(&events, &fullpad(CornerPrecedence::Cell));
+        no_touch.set_numlock(true).unwrap();
+        touch(&mut no_touch, 971, 49);
+        assert!(no_touch.numlock);
+        assert_eq!(no_touch.pressed, Some(Key::KEY_BACKSPACE));
+    }
+
+    #[test]
+    fn cell_just_outside_the_corner() {
+        let events = Events::default();
+        let mut no_touch = no_touch_with(&events, &fullpad(CornerPrecedence::Corner));
+        no_touch.set_numlock(true).unwrap();
+        touch(&mut no_touch, 970, 49);
+        assert!(no_touch.numlock);
+        assert_eq!(no_touch.pressed, Some(Key::KEY_BACKSPACE));
+    }
+}
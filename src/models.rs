@@ -0,0 +1,21 @@
+use crate::config::Config;
+
+const KNOWN: [&str; 2] = ["ASUE", "ELAN"];
+
+pub fn known(name: &str) -> bool {
+    KNOWN.iter().any(|prefix| name.starts_with(prefix))
+}
+
+pub fn generic(config: &Config) -> Config {
+    let mut config = config.clone();
+    config.i2c = false;
+    config
+}
+
+pub fn select(name: &str, config: &Config) -> Config {
+    if known(name) || config.activate_hex.is_some() {
+        return config.clone();
+    }
+    log::info!("unrecognized touchpad {name:?}, using the generic model");
+    generic(config)
+}
@@ -0,0 +1,24 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnBattery {
+    #[default]
+    Normal,
+    Disable,
+    Dim,
+}
+
+pub fn on_battery() -> std::io::Result<bool> {
+    for entry in std::fs::read_dir(Path::new("/sys/class/power_supply"))? {
+        match std::fs::read_to_string(entry?.path().join("status")) {
+            Ok(status) if status.trim() == "Discharging" => return Ok(true),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,66 @@
+use std::{fs::File, io::Write, path::Path};
+
+use evdev::{uinput::VirtualDevice, EventType, InputEvent, Synchronization};
+
+pub trait KeySink {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()>;
+}
+
+impl KeySink for VirtualDevice {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        VirtualDevice::emit(self, events)
+    }
+}
+
+pub struct RawDevice(File);
+
+impl RawDevice {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self(File::options().write(true).open(path)?))
+    }
+}
+
+fn bytes(event: &InputEvent, buf: &mut Vec<u8>) {
+    let event = event.as_ref();
+    buf.extend(event.time.tv_sec.to_ne_bytes());
+    buf.extend(event.time.tv_usec.to_ne_bytes());
+    buf.extend(event.type_.to_ne_bytes());
+    buf.extend(event.code.to_ne_bytes());
+    buf.extend(event.value.to_ne_bytes());
+}
+
+impl KeySink for RawDevice {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        for event in events.iter().chain([&InputEvent::new(
+            EventType::SYNCHRONIZATION,
+            Synchronization::SYN_REPORT.0,
+            0,
+        )]) {
+            bytes(event, &mut buf);
+        }
+        self.0.write_all(&buf)
+    }
+}
+
+pub struct LogSink;
+
+impl KeySink for LogSink {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        for event in events {
+            log::info!("emit {:?} {}", event.kind(), event.value());
+        }
+        Ok(())
+    }
+}
+
+pub struct Tee(pub Vec<Box<dyn KeySink>>);
+
+impl KeySink for Tee {
+    fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        for sink in &mut self.0 {
+            sink.emit(events)?;
+        }
+        Ok(())
+    }
+}
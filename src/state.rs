@@ -0,0 +1,49 @@
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+
+use nix::{errno::Errno, fcntl::OFlag};
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct State {
+    pub numlock: bool,
+    pub brightness: Option<u8>,
+    pub profile: usize,
+    pub layer: usize,
+}
+
+impl State {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(state) => toml::from_str(&state).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {e}", path.display()),
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let state = toml::to_string(self).map_err(|e| std::io::Error::other(e.to_string()))?;
+        std::fs::write(path, state)
+    }
+}
+
+pub fn broadcast(path: &Path, numlock: bool) -> std::io::Result<()> {
+    let mut fifo = match OpenOptions::new()
+        .write(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)
+    {
+        Ok(fifo) => fifo,
+        Err(e) if e.raw_os_error() == Some(Errno::ENXIO as i32) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match writeln!(fifo, "{}", if numlock { "on" } else { "off" }) {
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt::Write;
+
+pub type Area = (i32, i32, i32, i32);
+
+pub struct Svg {
+    size: i32,
+    body: String,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Svg {
+    pub fn new((minx, maxx, miny, maxy): Area) -> Self {
+        let mut svg = Self {
+            size: (maxx - minx).min(maxy - miny) / 20,
+            body: String::new(),
+        };
+        let _ = writeln!(
+            svg.body,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{minx} {miny} {} {}">"#,
+            maxx - minx,
+            maxy - miny,
+        );
+        svg.rect((minx, maxx, miny, maxy), "white");
+        svg
+    }
+
+    pub fn rect(&mut self, (x0, x1, y0, y1): Area, fill: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<rect x="{x0}" y="{y0}" width="{}" height="{}" fill="{fill}" stroke="black" stroke-width="{}"/>"#,
+            x1 - x0,
+            y1 - y0,
+            (self.size / 10).max(1),
+        );
+    }
+
+    pub fn label(&mut self, (x0, x1, y0, y1): Area, text: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<text x="{}" y="{}" font-size="{}" text-anchor="middle" dominant-baseline="middle">{}</text>"#,
+            (x0 + x1) / 2,
+            (y0 + y1) / 2,
+            self.size,
+            escape(text),
+        );
+    }
+
+    pub fn finish(mut self) -> String {
+        self.body.push_str("</svg>\n");
+        self.body
+    }
+}
@@ -0,0 +1,13 @@
+pub fn active() -> std::io::Result<u32> {
+    let active = std::fs::read_to_string("/sys/class/tty/tty0/active")?;
+    active
+        .trim()
+        .strip_prefix("tty")
+        .and_then(|vt| vt.parse().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected active vt {active:?}"),
+            )
+        })
+}
@@ -0,0 +1,340 @@
+use crate::sink::KeySink;
+
+#[cfg(feature = "wayland")]
+mod protocol {
+    use std::{
+        fs::File,
+        io::{IoSlice, Read, Write},
+        os::{
+            fd::{AsRawFd, OwnedFd},
+            unix::net::UnixStream,
+        },
+        path::PathBuf,
+        time::Instant,
+    };
+
+    use evdev::{InputEvent, InputEventKind, Key};
+    use nix::sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        socket::{sendmsg, ControlMessage, MsgFlags, UnixAddr},
+    };
+
+    use crate::sink::KeySink;
+
+    const DISPLAY: u32 = 1;
+    const KEYMAP_FORMAT_XKB_V1: u32 = 1;
+    const KEYMAP: &str = r#"xkb_keymap {
+    xkb_keycodes { include "evdev" };
+    xkb_types { include "complete" };
+    xkb_compat { include "complete" };
+    xkb_symbols { include "pc+us+inet(evdev)" };
+};
+"#;
+    const SHIFT: u32 = 1 << 0;
+    const CONTROL: u32 = 1 << 2;
+    const MOD1: u32 = 1 << 3;
+    const MOD2: u32 = 1 << 4;
+    const MOD4: u32 = 1 << 6;
+
+    fn modifier(key: Key) -> Option<u32> {
+        match key {
+            Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT => Some(SHIFT),
+            Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL => Some(CONTROL),
+            Key::KEY_LEFTALT | Key::KEY_RIGHTALT => Some(MOD1),
+            Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA => Some(MOD4),
+            _ => None,
+        }
+    }
+
+    fn error(message: String) -> std::io::Error {
+        std::io::Error::other(format!("wayland: {message}"))
+    }
+
+    struct Message(Vec<u8>);
+
+    impl Message {
+        fn new(object: u32, opcode: u16) -> Self {
+            let mut message = Self(Vec::new());
+            message.uint(object).uint(opcode.into());
+            message
+        }
+
+        fn uint(&mut self, value: u32) -> &mut Self {
+            self.0.extend(value.to_ne_bytes());
+            self
+        }
+
+        fn string(&mut self, value: &str) -> &mut Self {
+            self.uint(value.len() as u32 + 1);
+            self.0.extend(value.as_bytes());
+            self.0.push(0);
+            while !self.0.len().is_multiple_of(4) {
+                self.0.push(0);
+            }
+            self
+        }
+
+        fn finish(&mut self) -> &[u8] {
+            let size = self.0.len() as u32;
+            let opcode = u32::from_ne_bytes(self.0[4..8].try_into().unwrap());
+            self.0[4..8].copy_from_slice(&((size << 16) | opcode).to_ne_bytes());
+            &self.0
+        }
+    }
+
+    struct Event {
+        object: u32,
+        opcode: u16,
+        body: Vec<u8>,
+    }
+
+    impl Event {
+        fn uint(&self, at: &mut usize) -> Option<u32> {
+            let value = u32::from_ne_bytes(self.body.get(*at..*at + 4)?.try_into().ok()?);
+            *at += 4;
+            Some(value)
+        }
+
+        fn string(&self, at: &mut usize) -> Option<String> {
+            let len = self.uint(at)? as usize;
+            let value = self.body.get(*at..*at + len.saturating_sub(1))?;
+            *at += len.div_ceil(4) * 4;
+            Some(String::from_utf8_lossy(value).into())
+        }
+    }
+
+    struct Client {
+        socket: UnixStream,
+        next_id: u32,
+    }
+
+    impl Client {
+        fn new_id(&mut self) -> u32 {
+            self.next_id += 1;
+            self.next_id
+        }
+
+        fn read(&mut self) -> std::io::Result<Event> {
+            let mut header = [0; 8];
+            self.socket.read_exact(&mut header)?;
+            let object = u32::from_ne_bytes(header[..4].try_into().unwrap());
+            let word = u32::from_ne_bytes(header[4..].try_into().unwrap());
+            let mut body = vec![0; ((word >> 16) as usize).saturating_sub(8)];
+            self.socket.read_exact(&mut body)?;
+            Ok(Event {
+                object,
+                opcode: word as u16,
+                body,
+            })
+        }
+
+        fn roundtrip(&mut self, mut on_event: impl FnMut(&Event)) -> std::io::Result<()> {
+            let callback = self.new_id();
+            self.socket
+                .write_all(Message::new(DISPLAY, 0).uint(callback).finish())?;
+            loop {
+                let event = self.read()?;
+                match (event.object, event.opcode) {
+                    (object, 0) if object == callback => return Ok(()),
+                    (DISPLAY, 0) => {
+                        let mut at = 8;
+                        let message = event.string(&mut at).unwrap_or_default();
+                        return Err(error(message));
+                    }
+                    _ => on_event(&event),
+                }
+            }
+        }
+    }
+
+    fn socket_path() -> std::io::Result<PathBuf> {
+        let display = std::env::var_os("WAYLAND_DISPLAY").unwrap_or("wayland-0".into());
+        let display = PathBuf::from(display);
+        if display.is_absolute() {
+            return Ok(display);
+        }
+        let runtime = std::env::var_os("XDG_RUNTIME_DIR")
+            .ok_or_else(|| error("XDG_RUNTIME_DIR is not set".into()))?;
+        Ok(PathBuf::from(runtime).join(display))
+    }
+
+    fn keymap() -> std::io::Result<OwnedFd> {
+        let mut file = File::from(memfd_create(
+            c"asus-touchpad-keymap",
+            MemFdCreateFlag::MFD_CLOEXEC,
+        )?);
+        file.write_all(KEYMAP.as_bytes())?;
+        file.write_all(&[0])?;
+        Ok(file.into())
+    }
+
+    pub struct VirtualKeyboard {
+        socket: UnixStream,
+        keyboard: u32,
+        started: Instant,
+        held: Vec<Key>,
+        locked: u32,
+        toggle_numlock: bool,
+    }
+
+    impl VirtualKeyboard {
+        pub fn connect(toggle_numlock: bool) -> std::io::Result<Self> {
+            let path = socket_path()?;
+            let socket = UnixStream::connect(&path)
+                .map_err(|e| error(format!("{}: {e}", path.display())))?;
+            let mut client = Client {
+                socket,
+                next_id: DISPLAY,
+            };
+            let registry = client.new_id();
+            client
+                .socket
+                .write_all(Message::new(DISPLAY, 1).uint(registry).finish())?;
+            let mut seat = None;
+            let mut manager = None;
+            client.roundtrip(|event| {
+                if (event.object, event.opcode) != (registry, 0) {
+                    return;
+                }
+                let mut at = 0;
+                let (Some(name), Some(interface)) = (event.uint(&mut at), event.string(&mut at))
+                else {
+                    return;
+                };
+                match interface.as_str() {
+                    "wl_seat" if seat.is_none() => seat = Some(name),
+                    "zwp_virtual_keyboard_manager_v1" => manager = Some(name),
+                    _ => {}
+                }
+            })?;
+            let seat = seat.ok_or_else(|| error("no wl_seat".into()))?;
+            let manager = manager
+                .ok_or_else(|| error("compositor lacks zwp_virtual_keyboard_manager_v1".into()))?;
+            let mut bind = |name, interface| {
+                let id = client.new_id();
+                client.socket.write_all(
+                    Message::new(registry, 0)
+                        .uint(name)
+                        .string(interface)
+                        .uint(1)
+                        .uint(id)
+                        .finish(),
+                )?;
+                std::io::Result::Ok(id)
+            };
+            let seat = bind(seat, "wl_seat")?;
+            let manager = bind(manager, "zwp_virtual_keyboard_manager_v1")?;
+            let keyboard = client.new_id();
+            client
+                .socket
+                .write_all(Message::new(manager, 0).uint(seat).uint(keyboard).finish())?;
+            let keymap = keymap()?;
+            let mut message = Message::new(keyboard, 0);
+            message
+                .uint(KEYMAP_FORMAT_XKB_V1)
+                .uint(KEYMAP.len() as u32 + 1);
+            sendmsg::<UnixAddr>(
+                client.socket.as_raw_fd(),
+                &[IoSlice::new(message.finish())],
+                &[ControlMessage::ScmRights(&[keymap.as_raw_fd()])],
+                MsgFlags::empty(),
+                None,
+            )?;
+            client.roundtrip(|_| {})?;
+            let mut sink = Self {
+                socket: client.socket,
+                keyboard,
+                started: Instant::now(),
+                held: Vec::new(),
+                locked: 0,
+                toggle_numlock,
+            };
+            let mut buf = Vec::new();
+            sink.modifiers(&mut buf);
+            sink.socket.write_all(&buf)?;
+            Ok(sink)
+        }
+
+        fn modifiers(&self, buf: &mut Vec<u8>) {
+            let depressed = self
+                .held
+                .iter()
+                .filter_map(|&key| modifier(key))
+                .fold(0, |mods, bit| mods | bit);
+            buf.extend_from_slice(
+                Message::new(self.keyboard, 2)
+                    .uint(depressed)
+                    .uint(0)
+                    .uint(self.locked)
+                    .uint(0)
+                    .finish(),
+            );
+        }
+
+        fn track(&mut self, key: Key, pressed: bool) -> bool {
+            if key == Key::KEY_NUMLOCK {
+                let locked = match (self.toggle_numlock, pressed) {
+                    (true, true) => self.locked ^ MOD2,
+                    (true, false) => self.locked,
+                    (false, true) => self.locked | MOD2,
+                    (false, false) => self.locked & !MOD2,
+                };
+                return std::mem::replace(&mut self.locked, locked) != locked;
+            }
+            if modifier(key).is_none() {
+                return false;
+            }
+            if pressed {
+                if self.held.contains(&key) {
+                    return false;
+                }
+                self.held.push(key);
+            } else {
+                let before = self.held.len();
+                self.held.retain(|&held| held != key);
+                if self.held.len() == before {
+                    return false;
+                }
+            }
+            true
+        }
+    }
+
+    impl KeySink for VirtualKeyboard {
+        fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+            let time = self.started.elapsed().as_millis() as u32;
+            let mut buf = Vec::new();
+            for event in events {
+                let InputEventKind::Key(key) = event.kind() else {
+                    continue;
+                };
+                buf.extend_from_slice(
+                    Message::new(self.keyboard, 1)
+                        .uint(time)
+                        .uint(key.code().into())
+                        .uint(u32::from(event.value() != 0))
+                        .finish(),
+                );
+                if self.track(key, event.value() != 0) {
+                    self.modifiers(&mut buf);
+                }
+            }
+            self.socket.write_all(&buf)
+        }
+    }
+}
+
+#[cfg(feature = "wayland")]
+pub fn sink(toggle_numlock: bool) -> std::io::Result<Box<dyn KeySink>> {
+    Ok(Box::new(protocol::VirtualKeyboard::connect(
+        toggle_numlock,
+    )?))
+}
+
+#[cfg(not(feature = "wayland"))]
+pub fn sink(_toggle_numlock: bool) -> std::io::Result<Box<dyn KeySink>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "built without the wayland feature",
+    ))
+}